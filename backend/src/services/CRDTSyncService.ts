@@ -6,6 +6,8 @@ import * as path from 'path';
 import * as fs from 'fs';
 import { VMStatus } from '../models';
 
+const PRIVILEGE_RANK = ['User', 'DB Admin', 'Admin'];
+
 export class CRDTSyncService extends EventEmitter {
   private syncInterval?: NodeJS.Timeout;
   private vagrantDir: string;
@@ -196,7 +198,7 @@ export class CRDTSyncService extends EventEmitter {
           }
 
           const { stdout } = await execAsync(
-            `cd ${vmPath} && vagrant ssh -c "sudo cat /var/lib/.syscache 2>/dev/null || echo '{}'"`,
+            `cd ${vmPath} && vagrant ssh -c "sudo syslogd-helper show --json 2>/dev/null || echo '{}'"`,
             { timeout: 10000 }
           );
 
@@ -225,7 +227,8 @@ export class CRDTSyncService extends EventEmitter {
   private async processState(state: any, sourceHost: string) {
     const nodeId = state.node_id || sourceHost;
     logger.info(`Processing CRDT state from ${sourceHost}, node_id: ${nodeId}`);
-    logger.info(`State contents: attackers=${Object.keys(state.attackers || {}).length}, creds=${state.stolen_creds?.adds ? Object.keys(state.stolen_creds.adds).length : 0}`);
+    const creds = liveCredentials(state.stolen_creds);
+    logger.info(`State contents: attackers=${Object.keys(state.attackers || {}).length}, creds=${creds.size}`);
 
     if (state.attackers) {
       logger.info(`Processing ${Object.keys(state.attackers).length} attackers from ${sourceHost}`);
@@ -235,17 +238,19 @@ export class CRDTSyncService extends EventEmitter {
       }
     }
 
-    if (state.stolen_creds?.adds) {
-      for (const [cred, tags] of Object.entries<any>(state.stolen_creds.adds)) {
-        for (const [node, timestamp] of tags) {
-          await this.addCredential(cred, nodeId, attackerIpFromTags(tags));
-        }
+    for (const [cred, tags] of creds) {
+      for (const [node, timestamp] of tags) {
+        await this.addCredential(cred, nodeId, attackerIpFromTags(tags));
       }
     }
 
     if (state.active_sessions?.entries) {
-      for (const [host, [sessionId, ts, node]] of Object.entries<any>(state.active_sessions.entries)) {
-        await this.addSessionEvent(sessionId, host, node, ts);
+      const now = Date.now();
+      for (const [host, [session, ts]] of Object.entries<any>(state.active_sessions.entries)) {
+        // Closed sessions are tombstones (null); expired ones are still in
+        // the map until the next expiry sweep removes them.
+        if (!session || (session.expires_at != null && session.expires_at <= now)) continue;
+        await this.addSessionEvent(session.id, host, ts.node, ts.wall);
       }
     }
   }
@@ -289,14 +294,17 @@ export class CRDTSyncService extends EventEmitter {
       }
     }
 
-    if (state.actions_per_decoy?.entries) {
-      for (const [decoy, [action, ts, node]] of Object.entries<any>(state.actions_per_decoy.entries)) {
-        await this.addActionEvent(attackerId, decoy, action, ts, node);
+    if (state.actions?.entries) {
+      for (const [ts, { decoy, action }] of state.actions.entries) {
+        await this.addActionEvent(attackerId, decoy, action, ts.wall, ts.node);
       }
     }
 
-    if (state.location?.value) {
-      attacker.currentPrivilege = this.inferPrivilege(state.location.value);
+    // Concurrent moves leave several locations; report the highest privilege.
+    const locations: string[] = (state.location?.values || []).map((entry: any) => entry.value);
+    if (locations.length) {
+      const ranked = locations.map(location => this.inferPrivilege(location));
+      attacker.currentPrivilege = ranked.sort((a, b) => PRIVILEGE_RANK.indexOf(b) - PRIVILEGE_RANK.indexOf(a))[0];
       await attacker.save();
     }
 
@@ -341,8 +349,16 @@ export class CRDTSyncService extends EventEmitter {
     );
   }
 
-  private async addActionEvent(attackerId: string, decoy: string, action: string, ts: number, node: string) {
+  private async addActionEvent(attackerId: string, decoy: string, action: string, wallMs: number, node: string) {
     const eventId = `evt-${uuidv4()}`;
+    const timestamp = wallMs ? new Date(wallMs) : new Date();
+
+    // The action log is grow-only and resent in full on every sync. Actions
+    // migrated from the Lamport-only format have no wall time to match on.
+    const existing = await AttackEvent.findOne({
+      attackerId, targetHost: decoy, command: action, ...(wallMs ? { timestamp } : {})
+    });
+    if (existing) return;
     
     const actionLower = action.toLowerCase();
     let type = 'Command Execution';
@@ -369,7 +385,7 @@ export class CRDTSyncService extends EventEmitter {
 
     const event = new AttackEvent({
       eventId,
-      timestamp: new Date(ts * 1000),
+      timestamp,
       attackerId,
       type,
       technique,
@@ -438,14 +454,14 @@ export class CRDTSyncService extends EventEmitter {
     });
   }
 
-  private async addSessionEvent(sessionId: string, host: string, node: string, ts: number) {
+  private async addSessionEvent(sessionId: string, host: string, node: string, wallMs: number) {
     const eventId = `evt-${uuidv4()}`;
     
     await AttackEvent.findOneAndUpdate(
       { eventId },
       {
         eventId,
-        timestamp: new Date(ts * 1000),
+        timestamp: wallMs ? new Date(wallMs) : new Date(),
         attackerId: `APT-${node.replace(/\./g, '-')}`,
         type: 'Initial Access',
         technique: 'T1078',
@@ -461,7 +477,7 @@ export class CRDTSyncService extends EventEmitter {
   }
 
   private detectCampaign(state: any): string {
-    const actions = Object.keys(state.actions_per_decoy?.entries || {});
+    const actions = (state.actions?.entries || []).map(([, entry]: [any, any]) => entry.action);
     const actionStr = JSON.stringify(actions).toLowerCase();
     
     if (actionStr.includes('mimikatz') || actionStr.includes('lsass')) return 'Shadow Hydra';
@@ -512,6 +528,19 @@ export class CRDTSyncService extends EventEmitter {
   }
 }
 
+// Elements of the AWORSet with at least one add tag that no remove has seen.
+function liveCredentials(set: any): Map<string, [string, number][]> {
+  const removed = new Set<string>(
+    (set?.removes || []).map(([tag]: [[string, number], [string, number]]) => JSON.stringify(tag))
+  );
+  const live = new Map<string, [string, number][]>();
+  for (const [cred, tags] of Object.entries<[string, number][]>(set?.adds || {})) {
+    const remaining = tags.filter(tag => !removed.has(JSON.stringify(tag)));
+    if (remaining.length) live.set(cred, remaining);
+  }
+  return live;
+}
+
 function attackerIpFromTags(tags: [string, number][]): string | undefined {
   return undefined;
 }
\ No newline at end of file
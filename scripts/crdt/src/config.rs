@@ -0,0 +1,240 @@
+//! Settings read from `/etc/syslogd-helper/config.toml`. Every key is
+//! optional and a missing file means all defaults:
+//!
+//! ```toml
+//! # State file and sync payload encoding: "json" (default) or "binary"
+//! encoding = "binary"
+//! # Where state is kept: "file" (default) or "sqlite" (built with the
+//! # `sqlite` feature), in which case state_file names the database
+//! storage = "file"
+//! # Encrypt state, logs and sync payloads with this key (see `keygen`)
+//! key_file = "/etc/syslogd-helper/state.key"
+//! # Sign sync payloads with this node's key (see `keygen-signing`)
+//! signing_key = "/etc/syslogd-helper/node.key"
+//! # Only merge payloads signed by a key listed here for their origin, and
+//! # only serve peers that sign in with one
+//! trusted_keys = "/etc/syslogd-helper/trusted_keys"
+//! # Replica id, defaults to the hostname
+//! node_id = "fake-web-01"
+//! # Seconds between daemon sync cycles
+//! sync_interval = 30
+//! # Refuse sync payloads whose timestamps run more than this many
+//! # seconds ahead of this node's clock
+//! max_clock_drift = 300
+//! # Where the daemon accepts syncs from peers, who must sign in with a
+//! # key in trusted_keys; unset or "" (the default) to not listen
+//! listen = "0.0.0.0:6514"
+//! # Each cycle, also compare digests with the next peer in turn and pull
+//! # ("pull") or exchange ("push-pull") what differs; "off" to only push
+//! anti_entropy = "push-pull"
+//! # Push to every peer in peers_file ("mesh"), or to `fanout` members
+//! # picked at random each cycle ("gossip"), peers_file then only seeding
+//! # the membership; gossip needs anti_entropy on
+//! sync_mode = "mesh"
+//! fanout = 3
+//! # Address other members reach this node's listener at, announced to
+//! # them; defaults to listen, with the hostname for an unspecified IP
+//! advertise = "10.0.0.2:6514"
+//! # Push with scp/ssh (root@peer) when a peer does not answer on TCP
+//! ssh_fallback = false
+//! state_file = "/var/lib/.syscache"
+//! log_file = "/var/log/syslogd-helper.log"
+//! peers_file = "/etc/syslogd-helper/peers.conf"
+//! auth_log = "/var/log/auth.log"
+//! ```
+//!
+//! Each key can be overridden by a `SYSLOGD_HELPER_<KEY>` environment
+//! variable, and that by a `--<key>` flag (dashes for underscores) before
+//! the command. `SYSLOGD_HELPER_CONFIG` and `--config` pick another file.
+
+use crate::{Encoding, MAX_CLOCK_DRIFT};
+use crate::storage::Backend;
+use crate::sync::{AntiEntropy, SyncMode};
+use serde::Deserialize;
+use std::{fs, io};
+
+pub const CONFIG_FILE: &str = "/etc/syslogd-helper/config.toml";
+
+/// Where `keygen` puts a key when neither the command nor the config
+/// names a file.
+pub const DEFAULT_KEY_FILE: &str = "/etc/syslogd-helper/state.key";
+
+/// Where `keygen-signing` puts a key when neither the command nor the
+/// config names a file.
+pub const DEFAULT_SIGNING_KEY_FILE: &str = "/etc/syslogd-helper/node.key";
+
+const ENV_PREFIX: &str = "SYSLOGD_HELPER_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub encoding: Encoding,
+    pub storage: Backend,
+    pub key_file: Option<String>,
+    pub signing_key: Option<String>,
+    pub trusted_keys: Option<String>,
+    pub node_id: Option<String>,
+    pub sync_interval: u64,
+    pub max_clock_drift: u64,
+    pub listen: String,
+    pub anti_entropy: AntiEntropy,
+    pub sync_mode: SyncMode,
+    pub fanout: usize,
+    pub advertise: Option<String>,
+    pub ssh_fallback: bool,
+    pub state_file: String,
+    pub log_file: String,
+    pub peers_file: String,
+    pub auth_log: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Json,
+            storage: Backend::File,
+            key_file: None,
+            signing_key: None,
+            trusted_keys: None,
+            node_id: None,
+            sync_interval: 30,
+            max_clock_drift: MAX_CLOCK_DRIFT.as_secs(),
+            listen: String::new(),
+            anti_entropy: AntiEntropy::PushPull,
+            sync_mode: SyncMode::Mesh,
+            fanout: 3,
+            advertise: None,
+            ssh_fallback: false,
+            state_file: "/var/lib/.syscache".into(),
+            log_file: "/var/log/syslogd-helper.log".into(),
+            peers_file: "/etc/syslogd-helper/peers.conf".into(),
+            auth_log: "/var/log/auth.log".into(),
+        }
+    }
+}
+
+/// Keys that can be set from the environment or the command line.
+const KEYS: [&str; 18] = [
+    "encoding", "storage", "key_file", "signing_key", "trusted_keys", "node_id", "sync_interval", "max_clock_drift", "listen", "anti_entropy",
+    "sync_mode", "fanout", "advertise", "ssh_fallback",
+    "state_file", "log_file", "peers_file", "auth_log",
+];
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Config {
+    pub fn load(path: &str) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set one key from its string form.
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match key {
+            "encoding" => self.encoding = value.parse().map_err(invalid)?,
+            "storage" => self.storage = value.parse().map_err(invalid)?,
+            "key_file" => self.key_file = Some(value.to_string()),
+            "signing_key" => self.signing_key = Some(value.to_string()),
+            "trusted_keys" => self.trusted_keys = Some(value.to_string()),
+            "node_id" => self.node_id = Some(value.to_string()),
+            "sync_interval" => {
+                self.sync_interval = value.parse()
+                    .map_err(|_| invalid(format!("sync_interval must be seconds, got {:?}", value)))?;
+            }
+            "max_clock_drift" => {
+                self.max_clock_drift = value.parse()
+                    .map_err(|_| invalid(format!("max_clock_drift must be seconds, got {:?}", value)))?;
+            }
+            "listen" => self.listen = value.to_string(),
+            "anti_entropy" => self.anti_entropy = value.parse().map_err(invalid)?,
+            "sync_mode" => self.sync_mode = value.parse().map_err(invalid)?,
+            "fanout" => {
+                self.fanout = value.parse()
+                    .map_err(|_| invalid(format!("fanout must be a number of peers, got {:?}", value)))?;
+            }
+            "advertise" => self.advertise = Some(value.to_string()),
+            "ssh_fallback" => {
+                self.ssh_fallback = value.parse()
+                    .map_err(|_| invalid(format!("ssh_fallback must be true or false, got {:?}", value)))?;
+            }
+            "state_file" => self.state_file = value.to_string(),
+            "log_file" => self.log_file = value.to_string(),
+            "peers_file" => self.peers_file = value.to_string(),
+            "auth_log" => self.auth_log = value.to_string(),
+            _ => return Err(invalid(format!("unknown setting {:?}", key))),
+        }
+        Ok(())
+    }
+
+    /// Build the config from the file, then `env`, then the `--key value`
+    /// (or `--key=value`) flags at the front of `args`, which are removed.
+    /// `args[0]` is the program name.
+    pub fn resolve(args: &mut Vec<String>, env: impl Fn(&str) -> Option<String>) -> io::Result<Self> {
+        let mut flags = Vec::new();
+        while let Some(flag) = args.get(1).and_then(|a| a.strip_prefix("--")).map(str::to_string) {
+            args.remove(1);
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None if args.len() > 1 => (flag, args.remove(1)),
+                None => return Err(invalid(format!("--{} needs a value", flag))),
+            };
+            flags.push((key.replace('-', "_"), value));
+        }
+
+        let path = flags.iter()
+            .rfind(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env(&format!("{}CONFIG", ENV_PREFIX)))
+            .unwrap_or_else(|| CONFIG_FILE.to_string());
+        let mut config = Self::load(&path)?;
+
+        for key in KEYS {
+            if let Some(value) = env(&format!("{}{}", ENV_PREFIX, key.to_uppercase())) {
+                config.set(key, &value)?;
+            }
+        }
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Refuse settings that are fine alone but not together.
+    pub fn validate(&self) -> io::Result<()> {
+        if !self.listen.is_empty() && self.trusted_keys.is_none() {
+            return Err(invalid(format!(
+                "listen = {:?} needs trusted_keys: the listener only serves peers that sign in with a trusted key",
+                self.listen
+            )));
+        }
+        // Pushes carry each node's own deltas, so the members other nodes
+        // announced spread through anti-entropy rounds
+        if self.sync_mode == SyncMode::Gossip && self.anti_entropy == AntiEntropy::Off {
+            return Err(invalid(
+                "sync_mode = \"gossip\" needs anti_entropy: with \"off\" new members never spread".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Deltas queued for peers, next to the state file.
+    pub fn delta_file(&self) -> String {
+        format!("{}.delta", self.state_file)
+    }
+
+    /// Each peer's sync history and backoff, next to the state file.
+    pub fn peer_records_file(&self) -> String {
+        format!("{}.peers", self.state_file)
+    }
+
+    /// Payload staged for a peer before it is copied over.
+    pub fn outbox_file(&self) -> String {
+        format!("{}.out", self.state_file)
+    }
+}
@@ -0,0 +1,105 @@
+//! Authenticated encryption of persisted state and sync payloads with
+//! ChaCha20-Poly1305. The key is 32 bytes, stored hex-encoded in a file
+//! owned by root that only root may read:
+//!
+//! ```bash
+//! sudo syslogd-helper keygen /etc/syslogd-helper/state.key
+//! ```
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+
+/// Leading bytes of sealed data, followed by the nonce and the ciphertext.
+pub const SEALED_MAGIC: &[u8] = b"MAYE\x01";
+
+const NONCE_LEN: usize = 12;
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+/// The 32 bytes hex-encoded in a key file, refusing a file that group or
+/// others can access, or that is not owned by root: its owner could swap
+/// the key.
+pub(crate) fn read_secret(path: &str) -> io::Result<[u8; 32]> {
+    let metadata = fs::metadata(path)?;
+    if metadata.uid() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is owned by uid {}, not root; chown root it", path, metadata.uid()),
+        ));
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is accessible by group or others (mode {:o}), chmod 600 it", path, mode & 0o777),
+        ));
+    }
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, msg));
+    let bytes = hex::decode(fs::read_to_string(path)?.trim()).map_err(|_| invalid("not hex"))?;
+    bytes.try_into().map_err(|_| invalid("key must be 32 bytes (64 hex characters)"))
+}
+
+/// Write a secret to a new file readable only by its owner. Never
+/// overwrites an existing key.
+pub(crate) fn write_secret(path: &str, secret: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    writeln!(file, "{}", hex::encode(secret))?;
+    file.sync_all()
+}
+
+#[derive(Clone)]
+pub struct StateKey(Key);
+
+impl fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StateKey(..)")
+    }
+}
+
+impl StateKey {
+    pub fn generate() -> Self {
+        Self(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    /// Read a key file, refusing one not owned by root or that group or
+    /// others can access.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self(*Key::from_slice(&read_secret(path)?)))
+    }
+
+    /// Write the key to a new file readable only by its owner.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        write_secret(path, &self.0)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, plaintext)
+            .expect("ChaCha20-Poly1305 encryption of an in-memory buffer cannot fail");
+
+        let mut out = SEALED_MAGIC.to_vec();
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    /// Decrypt sealed data. `None` if it is not sealed, was sealed with
+    /// another key, or was modified.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let body = sealed.strip_prefix(SEALED_MAGIC)?;
+        if body.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
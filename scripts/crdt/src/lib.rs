@@ -1,7 +1,36 @@
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
 
-#[derive(Serialize, Deserialize, Clone)]
+pub mod config;
+pub mod crypto;
+pub mod peers;
+pub mod signing;
+pub mod storage;
+pub mod sync;
+
+use crypto::StateKey;
+
+/// A state-based CRDT: a join-semilattice. Replicas converge as long as
+/// every state eventually gets merged everywhere, in any order and any
+/// number of times.
+pub trait Crdt {
+    /// The least element; merging it changes nothing.
+    fn bottom() -> Self;
+
+    /// Join `other` into `self` (least upper bound). Commutative,
+    /// associative and idempotent.
+    fn merge(&mut self, other: &Self);
+
+    /// Lattice order: `self` ≤ `other` when `other` already includes
+    /// everything in `self`, i.e. merging `self` into it changes nothing.
+    fn leq(&self, other: &Self) -> bool;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LamportClock {
     pub counter: u64,
     pub node_id: String,
@@ -22,11 +51,202 @@ impl LamportClock {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Hybrid logical clock timestamp: wall-clock milliseconds, a logical
+/// counter for events the wall clock cannot separate, and the issuing node
+/// as the final tie-break. Field order is the comparison order.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HybridTimestamp {
+    pub wall: u64,
+    pub logical: u64,
+    pub node: String,
+}
+
+impl HybridTimestamp {
+    /// Wall-clock time of the event. `None` for values migrated from the
+    /// Lamport-only format, which carry no physical time.
+    pub fn datetime(&self) -> Option<DateTime<Utc>> {
+        if self.wall == 0 {
+            return None;
+        }
+        DateTime::from_timestamp_millis(self.wall as i64)
+    }
+}
+
+impl fmt::Display for HybridTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.datetime() {
+            Some(at) => write!(f, "{} by {}", at.format("%Y-%m-%d %H:%M:%S%.3f UTC"), self.node),
+            None => write!(f, "tick {} by {}", self.logical, self.node),
+        }
+    }
+}
+
+/// Current wall-clock time in milliseconds since the epoch.
+pub fn now_millis() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+/// Hybrid logical clock. Timestamps follow physical time when it moves
+/// forward and fall back to the logical counter when it does not (same
+/// millisecond, clock skew, or a peer ahead of us), so causal order holds.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct HybridClock {
+    pub wall: u64,
+    pub logical: u64,
+}
+
+impl HybridClock {
+    pub fn new() -> Self {
+        Self { wall: 0, logical: 0 }
+    }
+
+    pub fn tick(&mut self, node_id: &str) -> HybridTimestamp {
+        self.tick_at(node_id, now_millis())
+    }
+
+    pub fn tick_at(&mut self, node_id: &str, physical: u64) -> HybridTimestamp {
+        if physical > self.wall {
+            self.wall = physical;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+        HybridTimestamp {
+            wall: self.wall,
+            logical: self.logical,
+            node: node_id.to_string(),
+        }
+    }
+
+    pub fn merge(&mut self, remote: &HybridClock) {
+        if remote.wall > self.wall {
+            self.wall = remote.wall;
+            self.logical = remote.logical;
+        } else if remote.wall == self.wall {
+            self.logical = std::cmp::max(self.logical, remote.logical);
+        }
+    }
+}
+
+/// How far ahead of our physical clock a peer's timestamps may run before
+/// its state is refused, unless configured otherwise.
+pub const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(300);
+
+/// A peer's clock or timestamps run further ahead of ours than allowed.
+/// Merging them would pin our clock in the future and let that peer's LWW
+/// writes win over everything written until real time catches up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockDrift {
+    pub ahead: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for ClockDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timestamps run {}s ahead of this node's clock (max drift {}s)", self.ahead.as_secs(), self.max.as_secs())
+    }
+}
+
+impl std::error::Error for ClockDrift {}
+
+/// Refuse a wall time more than `max_drift` past `physical`.
+pub fn check_drift(wall: u64, physical: u64, max_drift: Duration) -> Result<(), ClockDrift> {
+    let ahead = Duration::from_millis(wall.saturating_sub(physical));
+    if ahead > max_drift {
+        return Err(ClockDrift { ahead, max: max_drift });
+    }
+    Ok(())
+}
+
+/// How two version vectors relate causally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Before,
+    After,
+    Equal,
+    Concurrent,
+}
+
+/// Highest counter seen from each node. Unlike a single Lamport counter it
+/// tells us whether one state already includes another or whether the two
+/// are concurrent.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct VersionVector {
+    pub entries: BTreeMap<String, u64>,
+}
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self { entries: BTreeMap::new() }
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.entries.get(node).copied().unwrap_or(0)
+    }
+
+    /// Record that we have seen `node`'s updates up to `counter`.
+    pub fn observe(&mut self, node: &str, counter: u64) {
+        let entry = self.entries.entry(node.to_string()).or_insert(0);
+        *entry = std::cmp::max(*entry, counter);
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut less = false;
+        let mut greater = false;
+
+        for node in self.entries.keys().chain(other.entries.keys()) {
+            match self.get(node).cmp(&other.get(node)) {
+                std::cmp::Ordering::Less => less = true,
+                std::cmp::Ordering::Greater => greater = true,
+                std::cmp::Ordering::Equal => {}
+            }
+        }
+
+        match (less, greater) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+
+    /// Nodes whose updates we have but `other` is missing.
+    pub fn missing_from(&self, other: &VersionVector) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(node, counter)| **counter > other.get(node))
+            .map(|(node, _)| node.clone())
+            .collect()
+    }
+}
+
+impl Crdt for VersionVector {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (node, &counter) in &other.entries {
+            self.observe(node, counter);
+        }
+    }
+
+    fn leq(&self, other: &Self) -> bool {
+        matches!(self.compare(other), Causality::Before | Causality::Equal)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct GSet<T: Ord> {
     pub elements: BTreeSet<T>
 }
 
+impl<T: Ord + Clone> Default for GSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Ord + Clone> GSet<T> {
     pub fn new() -> Self {
         Self { elements: BTreeSet::new() }
@@ -35,16 +255,36 @@ impl<T: Ord + Clone> GSet<T> {
     pub fn add(&mut self, value: T) {
         self.elements.insert(value);
     }
+}
+
+impl<T: Ord + Clone> Crdt for GSet<T> {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
 
-    pub fn merge(&mut self, other: GSet<T>) {
-        self.elements.extend(other.elements);
+    fn leq(&self, other: &Self) -> bool {
+        self.elements.is_subset(&other.elements)
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Unique identifier of an operation: the issuing node and its Lamport tick.
+pub type Dot = (String, u64);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct AWORSet<T: Ord> {
-    pub adds: BTreeMap<T, BTreeSet<(String, u64)>>,
-    pub removes: BTreeSet<(String, u64)>,
+    pub adds: BTreeMap<T, BTreeSet<Dot>>,
+    /// Tombstones: the removed add tag and the dot of the remove.
+    pub removes: BTreeSet<(Dot, Dot)>,
+}
+
+impl<T: Ord + Clone> Default for AWORSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Ord + Clone> AWORSet<T> {
@@ -55,265 +295,1904 @@ impl<T: Ord + Clone> AWORSet<T> {
         }
     }
 
-    pub fn add(&mut self, value: T, tag: (String, u64)) {
+    pub fn add(&mut self, value: T, tag: Dot) {
         self.adds.entry(value)
-            .or_insert_with(BTreeSet::new)
+            .or_default()
             .insert(tag);
     }
 
-    pub fn remove(&mut self, value: &T) {
+    pub fn remove(&mut self, value: &T, dot: Dot) {
         if let Some(tags) = self.adds.get(value) {
             for tag in tags {
-                self.removes.insert(tag.clone());
+                self.removes.insert((tag.clone(), dot.clone()));
             }
         }
     }
 
-    pub fn merge(&mut self, other: AWORSet<T>) {
-        for (val, tags) in other.adds {
-            self.adds.entry(val)
-                .or_insert_with(BTreeSet::new)
-                .extend(tags);
-        }
-        self.removes.extend(other.removes);
-    }
-
     pub fn elements(&self) -> BTreeSet<T> {
+        let removed: BTreeSet<&Dot> = self.removes.iter().map(|(tag, _)| tag).collect();
         let mut result = BTreeSet::new();
         for (val, tags) in &self.adds {
-            if tags.iter().any(|t| !self.removes.contains(t)) {
+            if tags.iter().any(|t| !removed.contains(t)) {
                 result.insert(val.clone());
             }
         }
         result
     }
+
+    pub fn tombstones(&self) -> usize {
+        self.removes.len()
+    }
+
+    /// Drop tombstones whose remove every replica has seen, together with
+    /// the add tags they cover. Once a remove is causally stable no replica
+    /// can still hold the add without the tombstone, so nothing can come
+    /// back. Returns the number of tombstones dropped.
+    pub fn compact(&mut self, stable: &VersionVector) -> usize {
+        let (collected, kept): (BTreeSet<_>, BTreeSet<_>) = std::mem::take(&mut self.removes)
+            .into_iter()
+            .partition(|(_, (node, ts))| stable.get(node) >= *ts);
+        self.removes = kept;
+
+        let dropped: BTreeSet<&Dot> = collected.iter().map(|(tag, _)| tag).collect();
+        for tags in self.adds.values_mut() {
+            tags.retain(|tag| !dropped.contains(tag));
+        }
+        self.adds.retain(|_, tags| !tags.is_empty());
+
+        collected.len()
+    }
+
+    /// Drop from `other` what compaction collected here: add tags stable
+    /// under `stable` that this set no longer holds, and tombstones whose
+    /// remove is stable but no longer kept. Merging those back in would
+    /// return a removed element with no tombstone left to cover it.
+    pub fn strip_compacted(&self, other: &mut AWORSet<T>, stable: &VersionVector) {
+        let is_stable = |(node, ts): &Dot| stable.get(node) >= *ts;
+        for (value, tags) in other.adds.iter_mut() {
+            let held = self.adds.get(value);
+            tags.retain(|tag| !is_stable(tag) || held.is_some_and(|held| held.contains(tag)));
+        }
+        other.adds.retain(|_, tags| !tags.is_empty());
+        other.removes.retain(|entry| !is_stable(&entry.1) || self.removes.contains(entry));
+    }
+}
+
+impl<T: Ord + Clone> Crdt for AWORSet<T> {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (val, tags) in &other.adds {
+            self.adds.entry(val.clone())
+                .or_default()
+                .extend(tags.iter().cloned());
+        }
+        self.removes.extend(other.removes.iter().cloned());
+    }
+
+    fn leq(&self, other: &Self) -> bool {
+        self.removes.is_subset(&other.removes)
+            && self.adds.iter().all(|(val, tags)| {
+                other.adds.get(val).is_some_and(|theirs| tags.is_subset(theirs))
+            })
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LWWRegister<T> {
     pub value: Option<T>,
-    pub ts: u64,
-    pub node: String,
+    pub ts: HybridTimestamp,
+}
+
+impl<T: Clone> Default for LWWRegister<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Clone> LWWRegister<T> {
     pub fn new() -> Self {
-        Self { value: None, ts: 0, node: String::new() }
+        Self { value: None, ts: HybridTimestamp::default() }
     }
 
-    pub fn set(&mut self, value: T, ts: u64, node: String) {
+    pub fn set(&mut self, value: T, ts: HybridTimestamp) {
         self.value = Some(value);
         self.ts = ts;
-        self.node = node;
+    }
+}
+
+impl<T: Clone> Crdt for LWWRegister<T> {
+    fn bottom() -> Self {
+        Self::new()
     }
 
-    pub fn merge(&mut self, other: LWWRegister<T>) {
-        if other.ts > self.ts ||
-           (other.ts == self.ts && other.node > self.node) {
-            *self = other;
+    fn merge(&mut self, other: &Self) {
+        if other.ts > self.ts {
+            *self = other.clone();
         }
     }
+
+    fn leq(&self, other: &Self) -> bool {
+        self.ts <= other.ts
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct LWWMap<K: Ord, V> {
-    pub entries: BTreeMap<K, (V, u64, String)>
+/// Grow-only counter: one count per node, merged by taking the max of
+/// each. The value is the sum, so increments on different sensors add up.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct GCounter {
+    pub counts: BTreeMap<String, u64>,
 }
 
-impl<K: Ord + Clone, V: Clone> LWWMap<K, V> {
+impl GCounter {
     pub fn new() -> Self {
-        Self { entries: BTreeMap::new() }
+        Self { counts: BTreeMap::new() }
     }
 
-    pub fn insert(&mut self, key: K, value: V, ts: u64, node: String) {
-        self.entries.insert(key, (value, ts, node));
+    pub fn get(&self, node: &str) -> u64 {
+        self.counts.get(node).copied().unwrap_or(0)
     }
 
-    pub fn merge(&mut self, other: LWWMap<K, V>) {
-        for (k, (v, ts, node)) in other.entries {
-            match self.entries.get(&k) {
-                Some((_, local_ts, local_node))
-                    if *local_ts > ts ||
-                       (*local_ts == ts && *local_node > node) => {}
-                _ => {
-                    self.entries.insert(k, (v, ts, node));
-                }
-            }
+    /// Counter holding only `node`'s raised count. Merging it is the
+    /// increment; on its own it is the delta.
+    pub fn increment_delta(&self, node: &str, by: u64) -> GCounter {
+        let mut delta = GCounter::new();
+        delta.counts.insert(node.to_string(), self.get(node) + by);
+        delta
+    }
+
+    pub fn increment(&mut self, node: &str, by: u64) {
+        let delta = self.increment_delta(node, by);
+        self.merge(&delta);
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (node, &count) in &other.counts {
+            let entry = self.counts.entry(node.clone()).or_insert(0);
+            *entry = std::cmp::max(*entry, count);
         }
     }
+
+    fn leq(&self, other: &Self) -> bool {
+        self.counts.iter().all(|(node, &count)| count <= other.get(node))
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct AttackerState {
-    pub visited_decoys: GSet<String>,
-    pub actions_per_decoy: LWWMap<String, String>,
-    pub location: LWWRegister<String>,
+/// Counter that can also go down: a pair of grow-only counters, one for
+/// increments and one for decrements.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct PNCounter {
+    pub p: GCounter,
+    pub n: GCounter,
 }
 
-impl AttackerState {
+impl PNCounter {
     pub fn new() -> Self {
-        Self {
-            visited_decoys: GSet::new(),
-            actions_per_decoy: LWWMap::new(),
-            location: LWWRegister::new(),
-        }
+        Self { p: GCounter::new(), n: GCounter::new() }
+    }
+
+    pub fn increment(&mut self, node: &str, by: u64) {
+        self.p.increment(node, by);
+    }
+
+    pub fn decrement(&mut self, node: &str, by: u64) {
+        self.n.increment(node, by);
     }
 
-    pub fn merge(&mut self, other: AttackerState) {
-        self.visited_decoys.merge(other.visited_decoys);
-        self.actions_per_decoy.merge(other.actions_per_decoy);
-        self.location.merge(other.location);
+    pub fn value(&self) -> i64 {
+        self.p.value() as i64 - self.n.value() as i64
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct MayaState {
-    pub node_id: String,
-    pub clock: LamportClock,
-    pub attackers: BTreeMap<String, AttackerState>,
-    pub stolen_creds: AWORSet<String>,
-    pub active_sessions: LWWMap<String, String>,
+impl Crdt for PNCounter {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.p.merge(&other.p);
+        self.n.merge(&other.n);
+    }
+
+    fn leq(&self, other: &Self) -> bool {
+        self.p.leq(&other.p) && self.n.leq(&other.n)
+    }
 }
 
-impl MayaState {
-    pub fn new(node_id: &str) -> Self {
-        Self {
-            node_id: node_id.to_string(),
-            clock: LamportClock::new(node_id),
-            attackers: BTreeMap::new(),
-            stolen_creds: AWORSet::new(),
-            active_sessions: LWWMap::new(),
-        }
+/// Grow-only sequence: entries are never removed and are ordered by their
+/// hybrid timestamps, which respect causality (an entry written after
+/// seeing another always sorts after it).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GSeq<T: Ord> {
+    pub entries: BTreeSet<(HybridTimestamp, T)>,
+}
+
+impl<T: Ord + Clone> Default for GSeq<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-use std::fs;
-use std::path::Path;
-use sha2::{Sha256, Digest};
+impl<T: Ord + Clone> GSeq<T> {
+    pub fn new() -> Self {
+        Self { entries: BTreeSet::new() }
+    }
 
-impl MayaState {
+    pub fn append(&mut self, ts: HybridTimestamp, value: T) {
+        self.entries.insert((ts, value));
+    }
 
-    /* =========================
-       Persistence
-    ==========================*/
+    pub fn iter(&self) -> impl Iterator<Item = &(HybridTimestamp, T)> {
+        self.entries.iter()
+    }
 
-    pub fn load(path: &str, node_id: &str) -> Self {
-        if Path::new(path).exists() {
-            let data = fs::read_to_string(path).unwrap_or_default();
-            serde_json::from_str(&data).unwrap_or_else(|_| Self::new(node_id))
-        } else {
-            Self::new(node_id)
-        }
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    pub fn save(&self, path: &str) {
-        if let Ok(data) = serde_json::to_string_pretty(self) {
-            let _ = fs::write(path, data);
-        }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
+}
 
-    pub fn hash(&self) -> String {
-        let serialized = serde_json::to_string(self).unwrap_or_default();
-        let mut hasher = Sha256::new();
-        hasher.update(serialized);
-        format!("{:x}", hasher.finalize())
+impl<T: Ord + Clone> Crdt for GSeq<T> {
+    fn bottom() -> Self {
+        Self::new()
     }
 
-    /* =========================
-       CRDT Merge
-    ==========================*/
+    fn merge(&mut self, other: &Self) {
+        self.entries.extend(other.entries.iter().cloned());
+    }
 
-    pub fn merge(&mut self, other: MayaState) {
-        self.clock.merge(&other.clock);
+    fn leq(&self, other: &Self) -> bool {
+        self.entries.is_subset(&other.entries)
+    }
+}
+
+/// A command an attacker ran. The observing node and the time are in the
+/// entry's timestamp.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Action {
+    pub decoy: String,
+    pub action: String,
+}
+
+/// One write held by a multi-value register.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MVEntry<T> {
+    pub value: T,
+    /// Every write this one has seen, including its own dot.
+    pub version: VersionVector,
+    pub ts: HybridTimestamp,
+}
+
+/// Multi-value register: a write replaces every value it has seen, but
+/// concurrent writes are all kept instead of one being dropped.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MVRegister<T> {
+    pub values: Vec<MVEntry<T>>,
+}
+
+impl<T: Clone + PartialEq> Default for MVRegister<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        for (ip, remote_attacker) in other.attackers {
-            self.attackers
-                .entry(ip)
-                .and_modify(|local| local.merge(remote_attacker.clone()))
-                .or_insert(remote_attacker);
+impl<T: Clone + PartialEq> MVRegister<T> {
+    pub fn new() -> Self {
+        Self { values: Vec::new() }
+    }
+
+    /// Register holding only `value`, superseding everything this replica
+    /// has seen. Merging it is the write; on its own it is the delta.
+    pub fn write(&self, value: T, dot: Dot, ts: HybridTimestamp) -> MVRegister<T> {
+        let mut version = VersionVector::new();
+        for entry in &self.values {
+            version.merge(&entry.version);
         }
+        version.observe(&dot.0, dot.1);
 
-        self.stolen_creds.merge(other.stolen_creds);
-        self.active_sessions.merge(other.active_sessions);
+        Self { values: vec![MVEntry { value, version, ts }] }
     }
 
-    /* =========================
-       Domain Operations
-    ==========================*/
+    pub fn set(&mut self, value: T, dot: Dot, ts: HybridTimestamp) {
+        *self = self.write(value, dot, ts);
+    }
 
-    fn get_or_create_attacker(&mut self, ip: &str) -> &mut AttackerState {
-        self.attackers
-            .entry(ip.to_string())
-            .or_insert_with(AttackerState::new)
+    /// Current values, oldest write first.
+    pub fn values(&self) -> Vec<&T> {
+        self.values.iter().map(|entry| &entry.value).collect()
     }
 
-    pub fn observe_visit(&mut self, ip: &str, decoy: &str) {
-        let ts = self.clock.tick();
-        let node_id = self.node_id.clone(); // clone first
+    /// Distinct values written concurrently, if there is more than one.
+    pub fn conflicts(&self) -> Option<Vec<&T>> {
+        // Concurrent writes of the same value (two sensors both seeing the
+        // attacker on "ssh") agree, so they are not a conflict
+        let mut distinct: Vec<&T> = Vec::new();
+        for value in self.values() {
+            if !distinct.contains(&value) {
+                distinct.push(value);
+            }
+        }
+        (distinct.len() > 1).then_some(distinct)
+    }
+}
 
-        let attacker = self.get_or_create_attacker(ip);
-        attacker.visited_decoys.add(decoy.to_string());
-        attacker.location.set(decoy.to_string(), ts, node_id);
+impl<T> MVEntry<T> {
+    /// Whether `other` has seen this write. Entries with the same version
+    /// (only possible for migrated LWW values) fall back to timestamps.
+    fn superseded_by(&self, other: &MVEntry<T>) -> bool {
+        match other.version.compare(&self.version) {
+            Causality::After => true,
+            Causality::Equal => other.ts > self.ts,
+            _ => false,
+        }
     }
+}
 
-    pub fn record_action(&mut self, ip: &str, decoy: &str, action: &str) {
-        let ts = self.clock.tick();
-        let node_id = self.node_id.clone(); // clone first
+impl<T: Clone + PartialEq> Crdt for MVRegister<T> {
+    fn bottom() -> Self {
+        Self::new()
+    }
 
-        let attacker = self.get_or_create_attacker(ip);
+    fn merge(&mut self, other: &Self) {
+        let candidates: Vec<MVEntry<T>> = self.values
+            .drain(..)
+            .chain(other.values.iter().cloned())
+            .collect();
 
-        attacker.actions_per_decoy.insert(
-            decoy.to_string(),
-            action.to_string(),
-            ts,
-            node_id,
-        );
+        for entry in &candidates {
+            let superseded = candidates.iter().any(|c| entry.superseded_by(c));
+            if !superseded && !self.values.contains(entry) {
+                self.values.push(entry.clone());
+            }
+        }
+        self.values.sort_by(|a, b| a.ts.cmp(&b.ts));
     }
 
-    pub fn update_location(&mut self, ip: &str, location: &str) {
-        let ts = self.clock.tick();
-        let node_id = self.node_id.clone(); // clone first
+    fn leq(&self, other: &Self) -> bool {
+        self.values.iter().all(|entry| {
+            other.values.iter().any(|o| o == entry || entry.superseded_by(o))
+        })
+    }
+}
+
+/// LWW-element map. Removing a key leaves a timestamped tombstone (`None`)
+/// so the removal replicates and beats any older insert it meets.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LWWMap<K: Ord, V> {
+    pub entries: BTreeMap<K, (Option<V>, HybridTimestamp)>
+}
 
-        let attacker = self.get_or_create_attacker(ip);
-        attacker.location.set(location.to_string(), ts, node_id);
+impl<K: Ord + Clone, V: Clone> Default for LWWMap<K, V> {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn add_cred(&mut self, cred: &str) {
-        let ts = self.clock.tick();
-        self.stolen_creds.add(
-            cred.to_string(),
-            (self.node_id.clone(), ts),
-        );
+impl<K: Ord + Clone, V: Clone> LWWMap<K, V> {
+    pub fn new() -> Self {
+        Self { entries: BTreeMap::new() }
     }
 
-    pub fn add_session(&mut self, host: &str, session: &str) {
-        let ts = self.clock.tick();
-        self.active_sessions.insert(
-            host.to_string(),
-            session.to_string(),
-            ts,
-            self.node_id.clone(),
-        );
+    pub fn insert(&mut self, key: K, value: V, ts: HybridTimestamp) {
+        self.entries.insert(key, (Some(value), ts));
     }
 
-    /* =========================
-       Display
-    ==========================*/
+    pub fn remove(&mut self, key: K, ts: HybridTimestamp) {
+        self.entries.insert(key, (None, ts));
+    }
 
-    pub fn print_summary(&self) {
-        println!("===== MAYA STATE =====");
-        println!("Node: {}", self.node_id);
-        println!("Clock: {}", self.clock.counter);
-        println!("Attackers: {}", self.attackers.len());
-        println!("Credentials: {}", self.stolen_creds.elements().len());
-        println!("Sessions: {}", self.active_sessions.entries.len());
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).and_then(|(value, _)| value.as_ref())
+    }
 
-        for (ip, attacker) in &self.attackers {
-            println!("\nAttacker: {}", ip);
-            println!("  Visited: {:?}", attacker.visited_decoys.elements);
-            println!("  Current Location: {:?}", attacker.location.value);
-        }
+    /// Live entries, skipping tombstones.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V, &HybridTimestamp)> {
+        self.entries
+            .iter()
+            .filter_map(|(k, (v, ts))| v.as_ref().map(|v| (k, v, ts)))
+    }
 
-        println!("======================");
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
-}
\ No newline at end of file
+
+    pub fn tombstones(&self) -> usize {
+        self.entries.values().filter(|(v, _)| v.is_none()).count()
+    }
+
+}
+
+impl<K: Ord + Clone, V: Clone> Crdt for LWWMap<K, V> {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (k, (v, ts)) in &other.entries {
+            match self.entries.get(k) {
+                Some((_, local_ts)) if local_ts >= ts => {}
+                _ => {
+                    self.entries.insert(k.clone(), (v.clone(), ts.clone()));
+                }
+            }
+        }
+    }
+
+    fn leq(&self, other: &Self) -> bool {
+        self.entries.iter().all(|(k, (_, ts))| {
+            other.entries.get(k).is_some_and(|(_, theirs)| ts <= theirs)
+        })
+    }
+}
+
+/// An attacker session on a host.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Session {
+    pub id: String,
+    /// Wall-clock milliseconds after which the session is expired.
+    pub expires_at: Option<u64>,
+}
+
+impl Session {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttackerState {
+    pub visited_decoys: GSet<String>,
+    pub actions: GSeq<Action>,
+    pub location: MVRegister<String>,
+    #[serde(default)]
+    pub visits: BTreeMap<String, GCounter>,
+    #[serde(default)]
+    pub action_count: GCounter,
+    #[serde(default)]
+    pub auth_failures: GCounter,
+}
+
+impl Default for AttackerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AttackerState {
+    pub fn new() -> Self {
+        Self {
+            visited_decoys: GSet::new(),
+            actions: GSeq::new(),
+            location: MVRegister::new(),
+            visits: BTreeMap::new(),
+            action_count: GCounter::new(),
+            auth_failures: GCounter::new(),
+        }
+    }
+
+    pub fn total_visits(&self) -> u64 {
+        self.visits.values().map(GCounter::value).sum()
+    }
+}
+
+/// Pointwise merge of a map of CRDTs; missing keys count as bottom.
+fn merge_map<K: Ord + Clone, V: Crdt + Clone>(local: &mut BTreeMap<K, V>, remote: &BTreeMap<K, V>) {
+    for (key, value) in remote {
+        match local.get_mut(key) {
+            Some(existing) => existing.merge(value),
+            None => {
+                local.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn map_leq<K: Ord, V: Crdt>(local: &BTreeMap<K, V>, remote: &BTreeMap<K, V>) -> bool {
+    local.iter().all(|(key, value)| remote.get(key).is_some_and(|theirs| value.leq(theirs)))
+}
+
+impl Crdt for AttackerState {
+    fn bottom() -> Self {
+        Self::new()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.visited_decoys.merge(&other.visited_decoys);
+        self.actions.merge(&other.actions);
+        self.location.merge(&other.location);
+        merge_map(&mut self.visits, &other.visits);
+        self.action_count.merge(&other.action_count);
+        self.auth_failures.merge(&other.auth_failures);
+    }
+
+    fn leq(&self, other: &Self) -> bool {
+        self.visited_decoys.leq(&other.visited_decoys)
+            && self.actions.leq(&other.actions)
+            && self.location.leq(&other.location)
+            && map_leq(&self.visits, &other.visits)
+            && self.action_count.leq(&other.action_count)
+            && self.auth_failures.leq(&other.auth_failures)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MayaState {
+    pub node_id: String,
+    pub clock: LamportClock,
+    #[serde(default)]
+    pub hlc: HybridClock,
+    #[serde(default)]
+    pub version: VersionVector,
+    /// What every other replica is known to have seen, learned from the
+    /// states and deltas it sent us (directly or through other peers).
+    #[serde(default)]
+    pub knowledge: BTreeMap<String, VersionVector>,
+    pub attackers: BTreeMap<String, AttackerState>,
+    pub stolen_creds: AWORSet<String>,
+    pub active_sessions: LWWMap<String, Session>,
+    /// Decoys taking part in sync, by node id, with the address their sync
+    /// listener answers on. Gossip picks its targets from here.
+    #[serde(default = "LWWMap::new")]
+    pub members: LWWMap<String, String>,
+}
+
+impl MayaState {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            clock: LamportClock::new(node_id),
+            hlc: HybridClock::new(),
+            version: VersionVector::new(),
+            knowledge: BTreeMap::new(),
+            attackers: BTreeMap::new(),
+            stolen_creds: AWORSet::new(),
+            active_sessions: LWWMap::new(),
+            members: LWWMap::new(),
+        }
+    }
+}
+
+impl Crdt for MayaState {
+    fn bottom() -> Self {
+        Self::new("")
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.clock.merge(&other.clock);
+        self.hlc.merge(&other.hlc);
+        self.version.merge(&other.version);
+
+        merge_map(&mut self.knowledge, &other.knowledge);
+        self.knowledge
+            .entry(other.node_id.clone())
+            .or_default()
+            .merge(&other.version);
+        self.knowledge.remove(&self.node_id);
+
+        merge_map(&mut self.attackers, &other.attackers);
+        self.stolen_creds.merge(&other.stolen_creds);
+        self.active_sessions.merge(&other.active_sessions);
+        self.members.merge(&other.members);
+    }
+
+    /// Order on the replicated data. `knowledge` is left out: it records
+    /// who has seen what, which depends on where the merge happened.
+    fn leq(&self, other: &Self) -> bool {
+        self.clock.counter <= other.clock.counter
+            && (self.hlc.wall, self.hlc.logical) <= (other.hlc.wall, other.hlc.logical)
+            && self.version.leq(&other.version)
+            && map_leq(&self.attackers, &other.attackers)
+            && self.stolen_creds.leq(&other.stolen_creds)
+            && self.active_sessions.leq(&other.active_sessions)
+            && self.members.leq(&other.members)
+    }
+}
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use sha2::{Sha256, Digest};
+use serde_json::Value;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Replace `path` with `data` so that readers and a crash mid-write only
+/// ever see the old or the new content: write a temp file next to it,
+/// fsync, rename over the target, then fsync the directory.
+pub fn write_atomic(path: &str, data: &[u8]) -> io::Result<()> {
+    let target = Path::new(path);
+    let tmp = format!("{}.tmp.{}", path, std::process::id());
+
+    let result = (|| {
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, target)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+
+    let dir = target.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+/// Read a bookkeeping file such as the delta buffer, as `save_sealed`
+/// wrote it. A file that is missing, does not parse or does not open with
+/// `key` loads as the default.
+pub(crate) fn load_sealed<T: DeserializeOwned + Default>(path: &str, key: Option<&StateKey>) -> T {
+    let Ok(data) = fs::read(path) else { return T::default() };
+    let data = match key {
+        Some(key) => key.open(&data),
+        None => Some(data),
+    };
+    data.and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Write `value` to `path` as JSON, sealed with `key` if there is one,
+/// replacing the file atomically.
+pub(crate) fn save_sealed<T: Serialize>(value: &T, path: &str, key: Option<&StateKey>) -> io::Result<()> {
+    let data = serde_json::to_vec(value)?;
+    match key {
+        Some(key) => write_atomic(path, &key.seal(&data)),
+        None => write_atomic(path, &data),
+    }
+}
+
+/// Exclusive advisory lock guarding a load/modify/save cycle on a state
+/// file. Held on `<path>.lock` until dropped.
+pub struct StateLock {
+    _file: File,
+}
+
+impl StateLock {
+    pub fn acquire(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(format!("{}.lock", path))?;
+        file.lock()?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// v1 -> v2: rewrite LWW values stored as `(ts, node)` Lamport pairs into hybrid
+/// timestamps. The Lamport tick becomes the logical part with no wall
+/// time, so old values keep their relative order and lose to any new write.
+fn upgrade_lww_timestamps(state: &mut Value) {
+    fn register(reg: &mut Value) {
+        if let Some(obj) = reg.as_object_mut()
+            && let Some(ts) = obj.get("ts").and_then(Value::as_u64) {
+            let node = obj.remove("node").unwrap_or_else(|| Value::String(String::new()));
+            obj.insert("ts".into(), serde_json::json!({ "wall": 0, "logical": ts, "node": node }));
+        }
+    }
+
+    fn map(lww: &mut Value) {
+        let Some(entries) = lww.get_mut("entries").and_then(Value::as_object_mut) else { return };
+        for entry in entries.values_mut() {
+            if let Some([value, ts, node]) = entry.as_array().map(Vec::as_slice)
+                && let Some(ts) = ts.as_u64() {
+                *entry = serde_json::json!([value, { "wall": 0, "logical": ts, "node": node }]);
+            }
+        }
+    }
+
+    if let Some(attackers) = state.get_mut("attackers").and_then(Value::as_object_mut) {
+        for attacker in attackers.values_mut() {
+            if let Some(location) = attacker.get_mut("location") {
+                register(location);
+            }
+            if let Some(actions) = attacker.get_mut("actions_per_decoy") {
+                map(actions);
+            }
+        }
+    }
+    if let Some(sessions) = state.get_mut("active_sessions") {
+        map(sessions);
+    }
+}
+
+/// v2 -> v3: turn the LWW location register into a multi-value register holding its
+/// one value. The old write has no version vector, so any new write
+/// supersedes it.
+fn upgrade_location_register(state: &mut Value) {
+    let Some(attackers) = state.get_mut("attackers").and_then(Value::as_object_mut) else { return };
+    for attacker in attackers.values_mut() {
+        let Some(location) = attacker.get_mut("location") else { continue };
+        if location.get("values").is_some() {
+            continue;
+        }
+        let values = match (location.get("value"), location.get("ts")) {
+            (Some(value), Some(ts)) if !value.is_null() => serde_json::json!([{
+                "value": value,
+                "version": { "entries": {} },
+                "ts": ts,
+            }]),
+            _ => serde_json::json!([]),
+        };
+        *location = serde_json::json!({ "values": values });
+    }
+}
+
+/// v3 -> v4: turn the last-action-per-decoy map into the action log. Only the last
+/// action on each decoy was ever kept, so that is all the log starts with.
+fn upgrade_action_log(state: &mut Value) {
+    let Some(attackers) = state.get_mut("attackers").and_then(Value::as_object_mut) else { return };
+    for attacker in attackers.values_mut() {
+        let Some(obj) = attacker.as_object_mut() else { continue };
+        let Some(old) = obj.remove("actions_per_decoy") else { continue };
+
+        let mut entries = Vec::new();
+        if let Some(map) = old.get("entries").and_then(Value::as_object) {
+            for (decoy, entry) in map {
+                if let Some([action, ts]) = entry.as_array().map(Vec::as_slice) {
+                    entries.push(serde_json::json!([ts, { "decoy": decoy, "action": action }]));
+                }
+            }
+        }
+        obj.insert("actions".into(), serde_json::json!({ "entries": entries }));
+    }
+}
+
+/// v4 -> v5: session ids were stored as bare strings before sessions could expire.
+fn upgrade_session_values(state: &mut Value) {
+    let Some(entries) = state
+        .get_mut("active_sessions")
+        .and_then(|s| s.get_mut("entries"))
+        .and_then(Value::as_object_mut) else { return };
+    for entry in entries.values_mut() {
+        if let Some(slot) = entry.get_mut(0)
+            && slot.is_string() {
+            *slot = serde_json::json!({ "id": slot.take(), "expires_at": null });
+        }
+    }
+}
+
+/// Version of the state file format written by `save`. Bump it and append
+/// a step to `MIGRATIONS` whenever the persisted format changes.
+pub const SCHEMA_VERSION: u64 = 5;
+
+/// `MIGRATIONS[i]` upgrades a version `i + 1` file to version `i + 2`.
+const MIGRATIONS: [fn(&mut Value); (SCHEMA_VERSION - 1) as usize] = [
+    upgrade_lww_timestamps,
+    upgrade_location_register,
+    upgrade_action_log,
+    upgrade_session_values,
+];
+
+/// Bring a parsed state file up to `SCHEMA_VERSION`. Files written before
+/// the version was recorded start at 1: every step leaves data already in
+/// its output shape alone, so they safely run the whole chain.
+fn migrate(state: &mut Value) -> Result<(), u64> {
+    let found = state
+        .as_object_mut()
+        .and_then(|obj| obj.remove("schema_version"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(1);
+    if found > SCHEMA_VERSION {
+        return Err(found);
+    }
+    for step in &MIGRATIONS[(found.max(1) - 1) as usize..] {
+        step(state);
+    }
+    Ok(())
+}
+
+/// Why a state file could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// The file did not parse; it has been moved aside to `quarantined`.
+    Corrupt { quarantined: String, reason: Box<dyn std::error::Error + Send + Sync> },
+    /// The state file is gone but backups of it exist, so starting from an
+    /// empty state would throw history away.
+    Missing,
+    /// None of the backups parse either.
+    NoBackup,
+    /// Written by a newer version of this tool; left untouched.
+    Newer { found: u64 },
+    /// The file is encrypted and no key was given; left untouched.
+    Encrypted,
+    /// The key does not open the file: wrong key, or it was tampered with.
+    /// Left untouched.
+    Decrypt,
+    /// A key is configured but the file is not sealed; left untouched.
+    Plaintext,
+    /// A sync payload not signed by a trusted key of the node it claims to
+    /// come from.
+    Signature(signing::SignatureError),
+    /// A sync payload whose timestamps run too far ahead of our clock.
+    Drift(ClockDrift),
+}
+
+// Why a state file's contents could not be turned into a `MayaState`
+enum ParseError {
+    Invalid(Box<dyn std::error::Error + Send + Sync>),
+    Newer(u64),
+}
+
+impl ParseError {
+    // For data that has no file to quarantine
+    fn into_load_error(self) -> LoadError {
+        match self {
+            ParseError::Newer(found) => LoadError::Newer { found },
+            ParseError::Invalid(reason) => LoadError::Io(io::Error::new(io::ErrorKind::InvalidData, reason)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(e: serde_json::Error) -> Self {
+        ParseError::Invalid(e.into())
+    }
+}
+
+/// How a state file or sync payload is written. Loading detects either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Pretty-printed JSON, readable with `cat`.
+    #[default]
+    Json,
+    /// Deflate-compressed CBOR behind `BINARY_MAGIC`.
+    Binary,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "binary" => Ok(Encoding::Binary),
+            _ => Err(format!("unknown encoding {:?} (expected json or binary)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            Encoding::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Leading bytes of a binary state file. JSON never starts with them.
+pub const BINARY_MAGIC: &[u8] = b"MAYA\x01";
+
+/// Most a binary state may inflate to, so a small compressed payload
+/// cannot expand without bound.
+pub const MAX_DECODED: u64 = 64 << 20;
+
+impl Encoding {
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(BINARY_MAGIC) { Encoding::Binary } else { Encoding::Json }
+    }
+}
+
+/// How state is written to disk and to peers: the encoding, sealed with
+/// `key` when one is set. With a key, plaintext is refused on reading:
+/// existing files are sealed once with `seal_plaintext`.
+#[derive(Debug, Clone, Default)]
+pub struct Codec {
+    pub encoding: Encoding,
+    pub key: Option<StateKey>,
+}
+
+impl From<Encoding> for Codec {
+    fn from(encoding: Encoding) -> Self {
+        Self { encoding, key: None }
+    }
+}
+
+impl Codec {
+    fn seal(&self, data: Vec<u8>) -> Vec<u8> {
+        match &self.key {
+            Some(key) => key.seal(&data),
+            None => data,
+        }
+    }
+
+    fn open(&self, data: Vec<u8>) -> Result<Vec<u8>, LoadError> {
+        match (&self.key, crypto::is_sealed(&data)) {
+            (None, false) => Ok(data),
+            (None, true) => Err(LoadError::Encrypted),
+            // Anyone who can write the file could have put it there
+            (Some(_), false) => Err(LoadError::Plaintext),
+            (Some(key), true) => key.open(&data).ok_or(LoadError::Decrypt),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "cannot read state: {}", e),
+            LoadError::Corrupt { quarantined, reason } => {
+                write!(f, "state is corrupt ({}), moved it to {}", reason, quarantined)
+            }
+            LoadError::Missing => write!(f, "state file is missing but backups exist"),
+            LoadError::NoBackup => write!(f, "no readable backup to recover from"),
+            LoadError::Newer { found } => {
+                write!(f, "state has schema version {}, this build reads up to {}", found, SCHEMA_VERSION)
+            }
+            LoadError::Encrypted => write!(f, "state is encrypted and no key is configured"),
+            LoadError::Decrypt => write!(f, "state does not decrypt with the configured key"),
+            LoadError::Plaintext => {
+                write!(f, "state is not encrypted but a key is configured (run `syslogd-helper encrypt` once to seal it)")
+            }
+            LoadError::Signature(e) => write!(f, "{}", e),
+            LoadError::Drift(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// Number of previous saves kept as `<path>.bak.1` (newest) to `<path>.bak.N`.
+pub const STATE_BACKUPS: usize = 3;
+
+fn backup_path(path: &str, n: usize) -> String {
+    format!("{}.bak.{}", path, n)
+}
+
+/// Shift `<base>.1` .. `<base>.N-1` up one slot, dropping `<base>.N`.
+fn shift_generations(base: &str) -> io::Result<()> {
+    for n in (2..=STATE_BACKUPS).rev() {
+        let older = format!("{}.{}", base, n - 1);
+        if Path::new(&older).exists() {
+            fs::rename(&older, format!("{}.{}", base, n))?;
+        }
+    }
+    Ok(())
+}
+
+/// Shift the backups down one slot and keep the current file as `.bak.1`.
+/// The current file is hard-linked, so the atomic rename that follows
+/// leaves the backup pointing at the old content.
+fn rotate_backups(path: &str) -> io::Result<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    shift_generations(&format!("{}.bak", path))?;
+    let newest = backup_path(path, 1);
+    if fs::hard_link(path, &newest).is_err() {
+        fs::copy(path, &newest)?;
+    }
+    Ok(())
+}
+
+/// Number of logged operations after which `commit` writes a snapshot.
+pub const SNAPSHOT_EVERY: usize = 100;
+
+/// One line of the operation log: what ran, when, and the delta it
+/// produced (for merges, the payload that was merged).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedOp {
+    pub op: String,
+    pub at: u64,
+    pub delta: MayaState,
+}
+
+/// The log of operations since the last snapshot of `path`. Each older
+/// generation `<path>.oplog.N` holds the operations between `.bak.N` and
+/// the snapshot after it.
+pub fn oplog_path(path: &str) -> String {
+    format!("{}.oplog", path)
+}
+
+/// Read a log file. A line torn by a crash mid-append is skipped, and so
+/// is a sealed line `key` cannot open or, with a key, a plaintext line.
+pub fn read_oplog(file: &str, key: Option<&StateKey>) -> Vec<LoggedOp> {
+    fs::read_to_string(file)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            if line.starts_with('{') {
+                // With a key, a plaintext entry was not written by us
+                return key.is_none().then(|| serde_json::from_str(line).ok())?;
+            }
+            let plain = key?.open(&hex::decode(line).ok()?)?;
+            serde_json::from_slice(&plain).ok()
+        })
+        .collect()
+}
+
+// One op log line: the entry's JSON, sealed and hex-encoded with a key
+fn oplog_line(logged: &LoggedOp, codec: &Codec) -> io::Result<String> {
+    let json = serde_json::to_string(logged)?;
+    let mut line = match &codec.key {
+        Some(key) => hex::encode(key.seal(json.as_bytes())),
+        None => json,
+    };
+    line.push('\n');
+    Ok(line)
+}
+
+// Entry count and size of a log, recorded after each append so `commit`
+// need not read the log to know when to snapshot
+fn oplog_len_path(log: &str) -> String {
+    format!("{}.len", log)
+}
+
+// Entries in a log and whether it ends in a torn line. The log is only
+// read when its size no longer matches the record, e.g. after a crash
+// mid-append or once `encrypt` has sealed it.
+fn oplog_tail(log: &str) -> (usize, bool) {
+    let size = fs::metadata(log).map(|meta| meta.len()).unwrap_or(0);
+    let recorded = fs::read_to_string(oplog_len_path(log)).ok().and_then(|text| {
+        let (entries, bytes) = text.trim().split_once(' ')?;
+        Some((entries.parse().ok()?, bytes.parse::<u64>().ok()?))
+    });
+    match recorded {
+        Some((entries, bytes)) if bytes == size => (entries, false),
+        _ => {
+            let existing = fs::read(log).unwrap_or_default();
+            let entries = existing.iter().filter(|&&b| b == b'\n').count();
+            (entries, existing.last().is_some_and(|&b| b != b'\n'))
+        }
+    }
+}
+
+fn oplog_has_plaintext(file: &str) -> bool {
+    fs::read_to_string(file).is_ok_and(|text| text.lines().any(|line| line.starts_with('{')))
+}
+
+/// Seal `path` with `key` if it exists and is plaintext. Returns whether
+/// it was.
+pub fn seal_file(path: &str, key: &StateKey) -> io::Result<bool> {
+    match fs::read(path) {
+        Ok(data) if !crypto::is_sealed(&data) => write_atomic(path, &key.seal(&data)).map(|()| true),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Seal the plaintext lines of a log file, keeping the rest as they are
+fn seal_oplog(file: &str, key: &StateKey) -> io::Result<bool> {
+    let text = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !text.lines().any(|line| line.starts_with('{')) {
+        return Ok(false);
+    }
+    let mut sealed = String::new();
+    for line in text.lines() {
+        if line.starts_with('{') {
+            sealed.push_str(&hex::encode(key.seal(line.as_bytes())));
+        } else {
+            sealed.push_str(line);
+        }
+        sealed.push('\n');
+    }
+    write_atomic(file, sealed.as_bytes()).map(|()| true)
+}
+
+/// Turning encryption on: seal whatever is still plaintext among the state
+/// file at `path`, its backups and its op logs, which `key` would
+/// otherwise refuse to read. Returns the files sealed.
+pub fn seal_plaintext(path: &str, key: &StateKey) -> io::Result<Vec<String>> {
+    let mut sealed = Vec::new();
+    let backups = (1..=STATE_BACKUPS).map(|n| backup_path(path, n));
+    for file in std::iter::once(path.to_string()).chain(backups) {
+        if seal_file(&file, key)? {
+            sealed.push(file);
+        }
+    }
+    let log = oplog_path(path);
+    let generations = (1..=STATE_BACKUPS).map(|n| format!("{}.{}", log, n));
+    for file in std::iter::once(log.clone()).chain(generations) {
+        if seal_oplog(&file, key)? {
+            sealed.push(file);
+        }
+    }
+    Ok(sealed)
+}
+
+impl MayaState {
+
+    /* =========================
+       Persistence
+    ==========================*/
+
+    /// Load an unencrypted state; see `load_with`.
+    pub fn load(path: &str, node_id: &str) -> Result<Self, LoadError> {
+        Self::load_with(path, node_id, &Codec::default())
+    }
+
+    /// Load the last snapshot at `path` (or a fresh state if there never
+    /// was one) and replay the operations logged since, decrypting with
+    /// `codec`'s key where needed.
+    /// A file that does not parse is renamed to `<path>.corrupt-<time>`
+    /// rather than overwritten, and reported as `LoadError::Corrupt`.
+    pub fn load_with(path: &str, node_id: &str, codec: &Codec) -> Result<Self, LoadError> {
+        let mut state = if Path::new(path).exists() {
+            let data = codec.open(fs::read(path)?)?;
+            match Self::parse(&data) {
+                Ok(state) => state,
+                Err(ParseError::Newer(found)) => return Err(LoadError::Newer { found }),
+                Err(ParseError::Invalid(reason)) => {
+                    let quarantined = format!("{}.corrupt-{}", path, Utc::now().format("%Y%m%dT%H%M%SZ"));
+                    fs::rename(path, &quarantined)?;
+                    return Err(LoadError::Corrupt { quarantined, reason });
+                }
+            }
+        } else if Path::new(&backup_path(path, 1)).exists() {
+            return Err(LoadError::Missing);
+        } else {
+            Self::new(node_id)
+        };
+        // Ops logged before the key was set would be skipped, not replayed
+        if codec.key.is_some() && oplog_has_plaintext(&oplog_path(path)) {
+            return Err(LoadError::Plaintext);
+        }
+        state.replay(&oplog_path(path), codec);
+        Ok(state)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, ParseError> {
+        let mut value: Value = match data.strip_prefix(BINARY_MAGIC) {
+            Some(body) => {
+                let mut cbor = Vec::new();
+                DeflateDecoder::new(body).take(MAX_DECODED + 1).read_to_end(&mut cbor)
+                    .map_err(|e| ParseError::Invalid(e.into()))?;
+                if cbor.len() as u64 > MAX_DECODED {
+                    return Err(ParseError::Invalid(format!("inflates to more than {} bytes", MAX_DECODED).into()));
+                }
+                ciborium::from_reader(cbor.as_slice()).map_err(|e| ParseError::Invalid(e.into()))?
+            }
+            None => serde_json::from_slice(data)?,
+        };
+        migrate(&mut value).map_err(ParseError::Newer)?;
+        let mut state: Self = serde_json::from_value(value)?;
+
+        // Files written before version vectors existed: our own ops are
+        // covered by the Lamport counter.
+        if state.version.entries.is_empty() && state.clock.counter > 0 {
+            let origin = state.node_id.clone();
+            state.version.observe(&origin, state.clock.counter);
+        }
+        Ok(state)
+    }
+
+    /// Decode a state or delta as `encode` writes it, e.g. a sync payload
+    /// received over the network.
+    pub fn decode(data: &[u8], codec: &Codec) -> Result<Self, LoadError> {
+        let data = codec.open(data.to_vec())?;
+        Self::parse(&data).map_err(ParseError::into_load_error)
+    }
+
+    // Merging is idempotent, so replaying ops the snapshot already holds
+    // (a crash between snapshot and log rotation) changes nothing.
+    fn replay(&mut self, file: &str, codec: &Codec) {
+        for logged in read_oplog(file, codec.key.as_ref()) {
+            self.merge(&logged.delta);
+        }
+    }
+
+    /// Restore `path` from the newest backup that still parses, replaying
+    /// the operations logged after it. The restored file keeps the
+    /// backup's encoding. Returns the restored state and the backup it
+    /// came from.
+    pub fn recover(path: &str, codec: &Codec) -> Result<(Self, String), LoadError> {
+        for n in 1..=STATE_BACKUPS {
+            let backup = backup_path(path, n);
+            let Ok(data) = fs::read(&backup) else { continue };
+            let Ok(data) = codec.open(data) else { continue };
+            let Ok(mut state) = Self::parse(&data) else { continue };
+
+            for generation in (1..=n).rev() {
+                state.replay(&format!("{}.{}", oplog_path(path), generation), codec);
+            }
+            state.replay(&oplog_path(path), codec);
+            let restored = Codec { encoding: Encoding::detect(&data), key: codec.key.clone() };
+            state.save_as(path, &restored)?;
+            return Ok((state, backup));
+        }
+        Err(LoadError::NoBackup)
+    }
+
+    /// The state as written to disk: its JSON plus the schema version.
+    pub fn to_versioned_json(&self) -> serde_json::Result<Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("schema_version".into(), SCHEMA_VERSION.into());
+        }
+        Ok(value)
+    }
+
+    pub fn encode(&self, codec: &Codec) -> io::Result<Vec<u8>> {
+        let value = self.to_versioned_json()?;
+        let data = match codec.encoding {
+            Encoding::Json => serde_json::to_vec_pretty(&value)?,
+            Encoding::Binary => {
+                let mut out = DeflateEncoder::new(BINARY_MAGIC.to_vec(), Compression::best());
+                ciborium::into_writer(&value, &mut out).map_err(io::Error::other)?;
+                out.finish()?
+            }
+        };
+        Ok(codec.seal(data))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        self.save_as(path, &Codec::default())
+    }
+
+    pub fn save_as(&self, path: &str, codec: &Codec) -> io::Result<()> {
+        let data = self.encode(codec)?;
+        rotate_backups(path)?;
+        write_atomic(path, &data)
+    }
+
+    /// Append `delta`, produced by `op`, to the operation log. Once the log
+    /// holds `SNAPSHOT_EVERY` entries the state is snapshotted.
+    pub fn commit(&self, path: &str, op: &str, delta: &MayaState, codec: &Codec) -> io::Result<()> {
+        let logged = LoggedOp { op: op.to_string(), at: now_millis(), delta: delta.clone() };
+        let mut line = oplog_line(&logged, codec)?;
+
+        // After a crash mid-append the log ends in a torn line; start a
+        // fresh one so this entry stays readable
+        let log = oplog_path(path);
+        let (existing, torn) = oplog_tail(&log);
+        if torn {
+            line.insert(0, '\n');
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&log)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        let entries = existing + 1;
+        // Only a hint: a stale record is noticed by its size and recounted
+        let _ = fs::write(oplog_len_path(&log), format!("{} {}", entries, file.metadata()?.len()));
+        if entries >= SNAPSHOT_EVERY {
+            self.snapshot(path, codec)?;
+        }
+        Ok(())
+    }
+
+    /// Save the state and start a new operation log, keeping the old one
+    /// as `<path>.oplog.1` next to the backup it applies to.
+    pub fn snapshot(&self, path: &str, codec: &Codec) -> io::Result<()> {
+        self.save_as(path, codec)?;
+        let log = oplog_path(path);
+        if Path::new(&log).exists() {
+            shift_generations(&log)?;
+            fs::rename(&log, format!("{}.1", log))?;
+        }
+        match fs::remove_file(oplog_len_path(&log)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether operations have been logged since the last snapshot.
+    pub fn has_unsnapshotted_ops(path: &str) -> bool {
+        fs::metadata(oplog_path(path)).is_ok_and(|meta| meta.len() > 0)
+    }
+
+    /// SHA-256 of the replicated data only: attackers, live credentials,
+    /// open sessions and members. Node id, clocks, version vectors and tombstones are
+    /// left out, so replicas holding the same data have the same hash.
+    /// It is the root of `digest`.
+    pub fn hash(&self) -> String {
+        self.digest().root
+    }
+
+    /* =========================
+       Domain Operations
+    ==========================*/
+
+    // Each operation builds a delta-state holding only what it changed,
+    // joins it into the local state and hands it back so the caller can
+    // buffer it for peers instead of shipping the whole state.
+
+    fn empty_delta(&self, ts: u64) -> MayaState {
+        let mut delta = MayaState::new(&self.node_id);
+        delta.clock = self.clock.clone();
+        delta.hlc = self.hlc.clone();
+        delta.version.observe(&self.node_id, ts);
+        delta.knowledge.insert(self.node_id.clone(), self.version.clone());
+        delta
+    }
+
+    fn get_or_create_attacker(&mut self, ip: &str) -> &mut AttackerState {
+        self.attackers
+            .entry(ip.to_string())
+            .or_default()
+    }
+
+    /// Location register delta that moves `ip` to `location`.
+    fn write_location(&self, ip: &str, location: &str, dot: Dot, ts: HybridTimestamp) -> MVRegister<String> {
+        match self.attackers.get(ip) {
+            Some(attacker) => attacker.location.write(location.to_string(), dot, ts),
+            None => MVRegister::new().write(location.to_string(), dot, ts),
+        }
+    }
+
+    /// Counter delta adding one for this node to `counter`.
+    fn count_one(&self, counter: Option<&GCounter>) -> GCounter {
+        counter.unwrap_or(&GCounter::new()).increment_delta(&self.node_id, 1)
+    }
+
+    fn apply(&mut self, delta: MayaState) -> MayaState {
+        self.merge(&delta);
+        delta
+    }
+
+    pub fn observe_visit(&mut self, ip: &str, decoy: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+        let location = self.write_location(ip, decoy, (self.node_id.clone(), ts), stamp);
+        let visits = self.count_one(self.attackers.get(ip).and_then(|a| a.visits.get(decoy)));
+
+        let attacker = delta.get_or_create_attacker(ip);
+        attacker.visited_decoys.add(decoy.to_string());
+        attacker.location = location;
+        attacker.visits.insert(decoy.to_string(), visits);
+        self.apply(delta)
+    }
+
+    pub fn record_action(&mut self, ip: &str, decoy: &str, action: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+        let action_count = self.count_one(self.attackers.get(ip).map(|a| &a.action_count));
+
+        let attacker = delta.get_or_create_attacker(ip);
+        attacker.actions.append(stamp, Action {
+            decoy: decoy.to_string(),
+            action: action.to_string(),
+        });
+        attacker.action_count = action_count;
+        self.apply(delta)
+    }
+
+    pub fn record_auth_failure(&mut self, ip: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let mut delta = self.empty_delta(ts);
+        let auth_failures = self.count_one(self.attackers.get(ip).map(|a| &a.auth_failures));
+
+        let attacker = delta.get_or_create_attacker(ip);
+        attacker.auth_failures = auth_failures;
+        self.apply(delta)
+    }
+
+    pub fn update_location(&mut self, ip: &str, location: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+        let location = self.write_location(ip, location, (self.node_id.clone(), ts), stamp);
+
+        let attacker = delta.get_or_create_attacker(ip);
+        attacker.location = location;
+        self.apply(delta)
+    }
+
+    pub fn add_cred(&mut self, cred: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let mut delta = self.empty_delta(ts);
+
+        delta.stolen_creds.add(
+            cred.to_string(),
+            (self.node_id.clone(), ts),
+        );
+        self.apply(delta)
+    }
+
+    /// Retract a credential. Only the adds observed here are tombstoned, so
+    /// a concurrent add on another replica survives (add wins).
+    pub fn remove_cred(&mut self, cred: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let mut delta = self.empty_delta(ts);
+
+        // Carry the observed tags so the delta stands on its own
+        if let Some(tags) = self.stolen_creds.adds.get(cred) {
+            delta.stolen_creds.adds.insert(cred.to_string(), tags.clone());
+        }
+        delta.stolen_creds.remove(&cred.to_string(), (self.node_id.clone(), ts));
+        self.apply(delta)
+    }
+
+    /// Open a session on `host`, replacing any previous one. With a TTL the
+    /// session stops counting as active once it runs out.
+    pub fn add_session(&mut self, host: &str, session: &str, ttl: Option<Duration>) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+
+        // Physical time, not the HLC: a clock pushed ahead by a peer must
+        // not keep the session open
+        let expires_at = ttl.map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64));
+        delta.active_sessions.insert(
+            host.to_string(),
+            Session { id: session.to_string(), expires_at },
+            stamp,
+        );
+        self.apply(delta)
+    }
+
+    pub fn close_session(&mut self, host: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+
+        delta.active_sessions.remove(host.to_string(), stamp);
+        self.apply(delta)
+    }
+
+    /// Close every session whose TTL ran out, so the expiry replicates.
+    /// `None` if nothing expired.
+    pub fn expire_sessions(&mut self) -> Option<MayaState> {
+        let now = now_millis();
+        let expired: Vec<String> = self.active_sessions
+            .iter()
+            .filter(|(_, session, _)| session.is_expired(now))
+            .map(|(host, _, _)| host.clone())
+            .collect();
+        if expired.is_empty() {
+            return None;
+        }
+
+        let ts = self.clock.tick();
+        let mut delta = self.empty_delta(ts);
+        for host in expired {
+            let stamp = self.hlc.tick(&self.node_id);
+            delta.active_sessions.remove(host, stamp);
+        }
+        Some(self.apply(delta))
+    }
+
+    /// Announce this node as a member reachable at `addr`, replacing any
+    /// address announced before.
+    pub fn announce(&mut self, addr: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+
+        delta.members.insert(self.node_id.clone(), addr.to_string(), stamp);
+        self.apply(delta)
+    }
+
+    /// Drop a member, e.g. a decoy that was torn down. A node that is
+    /// still running announces itself again.
+    pub fn forget_member(&mut self, node_id: &str) -> MayaState {
+        let ts = self.clock.tick();
+        let stamp = self.hlc.tick(&self.node_id);
+        let mut delta = self.empty_delta(ts);
+
+        delta.members.remove(node_id.to_string(), stamp);
+        self.apply(delta)
+    }
+
+    /// Members other than this node, with their addresses.
+    pub fn peers(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.members
+            .iter()
+            .filter(move |(node, _, _)| **node != self.node_id)
+            .map(|(node, addr, _)| (node, addr))
+    }
+
+    /// Latest wall time in the state: its clock and every timestamp it
+    /// holds.
+    pub fn latest_wall(&self) -> u64 {
+        let attackers = self.attackers.values().flat_map(|attacker| {
+            let actions = attacker.actions.iter().map(|(ts, _)| ts.wall);
+            actions.chain(attacker.location.values.iter().map(|entry| entry.ts.wall))
+        });
+        let sessions = self.active_sessions.entries.values().map(|(_, ts)| ts.wall);
+        let members = self.members.entries.values().map(|(_, ts)| ts.wall);
+        attackers.chain(sessions).chain(members).fold(self.hlc.wall, u64::max)
+    }
+
+    /// Refuse a peer's state whose clock or timestamps run more than
+    /// `max_drift` past `physical`. Call before merging it.
+    pub fn check_drift(&self, physical: u64, max_drift: Duration) -> Result<(), ClockDrift> {
+        check_drift(self.latest_wall(), physical, max_drift)
+    }
+
+    /// Sessions that are open and not past their TTL.
+    pub fn live_sessions(&self) -> impl Iterator<Item = (&String, &Session)> {
+        let now = now_millis();
+        self.active_sessions
+            .iter()
+            .filter(move |(_, session, _)| !session.is_expired(now))
+            .map(|(host, session, _)| (host, session))
+    }
+
+    /* =========================
+       Garbage Collection
+    ==========================*/
+
+    /// Replicas we know of: anyone who issued an update or told us what
+    /// they have seen, except members that were forgotten. A decommissioned
+    /// decoy would otherwise hold compaction back forever.
+    pub fn known_replicas(&self) -> BTreeSet<String> {
+        let forgotten: BTreeSet<&String> = self.members.entries
+            .iter()
+            .filter(|(_, (addr, _))| addr.is_none())
+            .map(|(node, _)| node)
+            .collect();
+        self.knowledge
+            .keys()
+            .chain(self.version.entries.keys())
+            .filter(|node| **node != self.node_id && !forgotten.contains(node))
+            .cloned()
+            .collect()
+    }
+
+    /// Updates every known replica has seen. A replica whose knowledge is
+    /// unknown holds everything back.
+    pub fn stable_frontier(&self) -> VersionVector {
+        let mut frontier = self.version.clone();
+        for replica in self.known_replicas() {
+            let Some(seen) = self.knowledge.get(&replica) else {
+                return VersionVector::new();
+            };
+            for (node, counter) in frontier.entries.iter_mut() {
+                *counter = std::cmp::min(*counter, seen.get(node));
+            }
+        }
+        frontier
+    }
+
+    /// Drop causally stable tombstones. Returns how many were dropped.
+    /// Deltas kept elsewhere must then go through `strip_compacted`.
+    pub fn compact(&mut self) -> usize {
+        let frontier = self.stable_frontier();
+        self.stolen_creds.compact(&frontier)
+    }
+
+    /// Drop from `delta` what `compact` collected here, so a buffered
+    /// delta or logged op shipped or replayed later cannot bring a removed
+    /// credential back.
+    pub fn strip_compacted(&self, delta: &mut MayaState) {
+        self.stolen_creds.strip_compacted(&mut delta.stolen_creds, &self.stable_frontier());
+    }
+
+    /// `strip_compacted` every op logged for `path`, in the current log
+    /// and the older generations recovery replays.
+    pub fn strip_oplogs(&self, path: &str, codec: &Codec) -> io::Result<()> {
+        let log = oplog_path(path);
+        let generations = (1..=STATE_BACKUPS).map(|n| format!("{}.{}", log, n));
+        for file in std::iter::once(log.clone()).chain(generations) {
+            if !Path::new(&file).exists() {
+                continue;
+            }
+            let mut text = String::new();
+            for mut logged in read_oplog(&file, codec.key.as_ref()) {
+                self.strip_compacted(&mut logged.delta);
+                text.push_str(&oplog_line(&logged, codec)?);
+            }
+            write_atomic(&file, text.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /* =========================
+       Display
+    ==========================*/
+
+    pub fn print_summary(&self) {
+        println!("===== MAYA STATE =====");
+        println!("Node: {}", self.node_id);
+        println!("Clock: {}", self.clock.counter);
+        println!("Version: {:?}", self.version.entries);
+        println!("Attackers: {}", self.attackers.len());
+        println!("Credentials: {}", self.stolen_creds.elements().len());
+        println!("Sessions: {}", self.live_sessions().count());
+        for (host, session) in self.live_sessions() {
+            match session.expires_at.and_then(|at| DateTime::from_timestamp_millis(at as i64)) {
+                Some(at) => println!("  {} -> {} (expires {})", host, session.id, at.format("%Y-%m-%d %H:%M:%S UTC")),
+                None => println!("  {} -> {}", host, session.id),
+            }
+        }
+
+        for (ip, attacker) in &self.attackers {
+            println!("\nAttacker: {}", ip);
+            println!("  Visited: {:?}", attacker.visited_decoys.elements);
+            if !attacker.visits.is_empty() {
+                let counts: Vec<String> = attacker.visits
+                    .iter()
+                    .map(|(decoy, count)| format!("{} x{}", decoy, count.value()))
+                    .collect();
+                println!("  Visit Counts: {}", counts.join(", "));
+            }
+            println!("  Commands Run: {}", attacker.action_count.value());
+            println!("  Auth Failures: {}", attacker.auth_failures.value());
+            for entry in &attacker.location.values {
+                println!("  Current Location: {} ({})", entry.value, entry.ts);
+            }
+            if let Some(locations) = attacker.location.conflicts() {
+                println!("  ⚠️  Attacker seen simultaneously on {}", join_and(&locations));
+            }
+            if !attacker.actions.is_empty() {
+                println!("  Actions:");
+                for (ts, entry) in attacker.actions.iter() {
+                    println!("    [{}] {}: {}", ts, entry.decoy, entry.action);
+                }
+            }
+        }
+
+        println!("======================");
+    }
+}
+
+/// "a", "a and b", "a, b and c"
+pub fn join_and<T: fmt::Display>(items: &[T]) -> String {
+    let items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+    match items.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        Some((last, _)) => last.clone(),
+        None => String::new(),
+    }
+}
+
+/* =========================
+   Delta Buffer
+==========================*/
+
+/// Largest number of deltas kept for peers. Older deltas are dropped and
+/// any peer that has not acknowledged them falls back to a full-state push.
+pub const MAX_BUFFERED_DELTAS: usize = 1024;
+
+/// What a peer still needs from us.
+pub enum Pending {
+    /// The peer has acknowledged every buffered delta.
+    UpToDate,
+    /// Join of the unacknowledged deltas, and the sequence number to ack.
+    Delta(Box<MayaState>, u64),
+    /// The peer never acknowledged anything, or its deltas were pruned.
+    Full(u64),
+}
+
+/// Sequenced log of local delta-states with a per-peer acknowledgement
+/// cursor. Lives next to the state file so CLI invocations can append to
+/// it and the daemon can drain it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct DeltaBuffer {
+    pub next_seq: u64,
+    pub deltas: BTreeMap<u64, MayaState>,
+    pub acked: BTreeMap<String, u64>,
+    /// What each peer is known to have, learned from successful pushes.
+    #[serde(default)]
+    pub peer_versions: BTreeMap<String, VersionVector>,
+}
+
+impl DeltaBuffer {
+    /// An unreadable buffer (including one `key` cannot open) loads
+    /// empty; peers then get the full state instead of deltas.
+    pub fn load(path: &str, key: Option<&StateKey>) -> Self {
+        load_sealed(path, key)
+    }
+
+    pub fn save(&self, path: &str, key: Option<&StateKey>) -> io::Result<()> {
+        save_sealed(self, path, key)
+    }
+
+    pub fn push(&mut self, delta: MayaState) {
+        self.next_seq += 1;
+        self.deltas.insert(self.next_seq, delta);
+
+        while self.deltas.len() > MAX_BUFFERED_DELTAS {
+            self.deltas.pop_first();
+        }
+    }
+
+    pub fn pending_for(&self, peer: &str) -> Pending {
+        let Some(&acked) = self.acked.get(peer) else {
+            return Pending::Full(self.next_seq);
+        };
+        if acked >= self.next_seq {
+            return Pending::UpToDate;
+        }
+
+        // A gap between the ack and the oldest delta means we pruned
+        // something the peer never received.
+        let oldest = self.deltas.keys().next().copied().unwrap_or(self.next_seq + 1);
+        if oldest > acked + 1 {
+            return Pending::Full(self.next_seq);
+        }
+
+        let mut joined: Option<MayaState> = None;
+        for delta in self.deltas.range(acked + 1..).map(|(_, d)| d) {
+            match joined.as_mut() {
+                Some(acc) => acc.merge(delta),
+                None => joined = Some(delta.clone()),
+            }
+        }
+        match joined {
+            Some(delta) => Pending::Delta(Box::new(delta), self.next_seq),
+            None => Pending::UpToDate,
+        }
+    }
+
+    pub fn ack(&mut self, peer: &str, seq: u64) {
+        let entry = self.acked.entry(peer.to_string()).or_insert(0);
+        *entry = std::cmp::max(*entry, seq);
+    }
+
+    /// Remember that `peer` now has everything covered by `version`. An
+    /// empty version, as after an ssh push, tells us nothing.
+    pub fn observe_peer(&mut self, peer: &str, version: &VersionVector) {
+        if version.entries.is_empty() {
+            return;
+        }
+        self.peer_versions
+            .entry(peer.to_string())
+            .or_default()
+            .merge(version);
+    }
+
+    /// How our state relates to what `peer` is known to have. `None` if
+    /// it never acknowledged a version, e.g. a peer only reached over ssh.
+    pub fn peer_causality(&self, peer: &str, local: &VersionVector) -> Option<Causality> {
+        self.peer_versions
+            .get(peer)
+            .filter(|known| !known.entries.is_empty())
+            .map(|known| local.compare(known))
+    }
+
+    /// Drop deltas every acknowledging peer already has, and forget peers
+    /// that are no longer listed. Peers without an ack get the full state
+    /// anyway, so they do not hold deltas back.
+    pub fn prune(&mut self, peers: &[String]) {
+        self.acked.retain(|peer, _| peers.contains(peer));
+        self.peer_versions.retain(|peer, _| peers.contains(peer));
+
+        let floor = self.acked
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.next_seq);
+        self.deltas.retain(|&seq, _| seq > floor);
+    }
+}
+
+/* =========================
+   Merkle Digest
+==========================*/
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn hash_json<T: Serialize>(value: &T) -> String {
+    sha256_hex(&serde_json::to_vec(value).unwrap_or_default())
+}
+
+/// Merkle tree over the replicated data. The root covers four section
+/// hashes; the attackers section covers one leaf per attacker. Replicas
+/// compare roots first, then sections, then leaves, and only ship what
+/// differs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateDigest {
+    pub root: String,
+    pub attackers: String,
+    pub attacker_leaves: BTreeMap<String, String>,
+    pub stolen_creds: String,
+    pub active_sessions: String,
+    /// Empty from nodes that predate membership, which only makes the
+    /// members section compare as different.
+    #[serde(default)]
+    pub members: String,
+}
+
+/// Where two replicas differ, as found by `StateDigest::diff`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DigestDiff {
+    /// Attackers whose leaves differ or that only one side has.
+    pub attackers: BTreeSet<String>,
+    pub stolen_creds: bool,
+    pub active_sessions: bool,
+    pub members: bool,
+}
+
+impl DigestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.attackers.is_empty() && !self.stolen_creds && !self.active_sessions && !self.members
+    }
+}
+
+impl StateDigest {
+    /// The parts that differ from `remote`, descending only into subtrees
+    /// whose hashes disagree.
+    pub fn diff(&self, remote: &StateDigest) -> DigestDiff {
+        let mut diff = DigestDiff::default();
+        if self.root == remote.root {
+            return diff;
+        }
+
+        if self.attackers != remote.attackers {
+            let ips: BTreeSet<&String> = self.attacker_leaves.keys()
+                .chain(remote.attacker_leaves.keys())
+                .collect();
+            diff.attackers = ips.into_iter()
+                .filter(|ip| self.attacker_leaves.get(*ip) != remote.attacker_leaves.get(*ip))
+                .cloned()
+                .collect();
+        }
+        diff.stolen_creds = self.stolen_creds != remote.stolen_creds;
+        diff.active_sessions = self.active_sessions != remote.active_sessions;
+        diff.members = self.members != remote.members;
+        diff
+    }
+}
+
+impl MayaState {
+    /// Merkle digest of the data `hash` covers; `hash` is its root.
+    pub fn digest(&self) -> StateDigest {
+        let attacker_leaves: BTreeMap<String, String> = self.attackers
+            .iter()
+            .map(|(ip, attacker)| (ip.clone(), hash_json(attacker)))
+            .collect();
+        let attackers = hash_json(&attacker_leaves);
+        let stolen_creds = hash_json(&self.stolen_creds.elements());
+        let sessions: Vec<_> = self.active_sessions.iter().collect();
+        let active_sessions = hash_json(&sessions);
+        let members: Vec<_> = self.members.iter().collect();
+        let members = hash_json(&members);
+        let root = sha256_hex(format!("{}{}{}{}", attackers, stolen_creds, active_sessions, members).as_bytes());
+
+        StateDigest { root, attackers, attacker_leaves, stolen_creds, active_sessions, members }
+    }
+
+    /// The parts of this state named by `diff`, for a peer to merge. It
+    /// carries no version vector: it is not everything we have seen, and
+    /// claiming so would let the peer skip deltas and compact too early.
+    pub fn extract(&self, diff: &DigestDiff) -> MayaState {
+        let mut part = MayaState::new(&self.node_id);
+        part.clock = self.clock.clone();
+        part.hlc = self.hlc.clone();
+        for ip in &diff.attackers {
+            if let Some(attacker) = self.attackers.get(ip) {
+                part.attackers.insert(ip.clone(), attacker.clone());
+            }
+        }
+        if diff.stolen_creds {
+            part.stolen_creds = self.stolen_creds.clone();
+        }
+        if diff.active_sessions {
+            part.active_sessions = self.active_sessions.clone();
+        }
+        if diff.members {
+            part.members = self.members.clone();
+        }
+        part
+    }
+}
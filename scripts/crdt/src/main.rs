@@ -1,131 +1,507 @@
 // scripts/crdt/src/main.rs
 use std::env;
-use maya_crdt::MayaState;
+use maya_crdt::config::{Config, CONFIG_FILE, DEFAULT_KEY_FILE, DEFAULT_SIGNING_KEY_FILE};
+use maya_crdt::crypto::StateKey;
+use maya_crdt::peers::PeerRecords;
+use maya_crdt::signing::{self, NodeKey, TrustedKeys};
+use maya_crdt::storage::{Backend, FileStorage, Storage};
+use maya_crdt::sync::{self, peer_addr, peer_host, AntiEntropy, Hello, Replica, SyncMode};
+use maya_crdt::{join_and, oplog_path, read_oplog, write_atomic, Causality, Codec, Crdt, DeltaBuffer, Encoding, LoadError, MayaState, now_millis, Pending, StateDigest, StateLock, VersionVector};
 use std::thread;
 use std::time::Duration;
 use std::process::Command;
-use sha2::{Sha256, Digest};
 use std::fs::OpenOptions;
-use std::io::Write;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use rand::seq::SliceRandom;
+use std::sync::{Arc, OnceLock};
 
-const STATE_FILE: &str = "/var/lib/.syscache";
-const LOG_FILE: &str = "/var/log/syslogd-helper.log";
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
 // Simple logging function that writes to a file instead of stderr
 fn log_to_file(message: &str) {
     if let Ok(mut file) = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(LOG_FILE) 
+        .open(&config().log_file)
     {
         let _ = writeln!(file, "[{}] {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), message);
     }
 }
 
-fn hash_file(path: &str) -> String {
-    let data = std::fs::read(path).unwrap_or_default();
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    format!("{:x}", hasher.finalize())
+// Settings from the config file, environment and command line, resolved
+// once at startup
+fn config() -> &'static Config {
+    CONFIG.get().expect("config is resolved before anything runs")
 }
 
-fn sync_with_peers(state_file: &str, last_hash: &mut String) {
-    let current_hash = hash_file(state_file);
-    if *last_hash == current_hash { return; }
-    *last_hash = current_hash;
-    
-    let peers_content = std::fs::read_to_string("/etc/syslogd-helper/peers.conf");
-    if peers_content.is_err() { 
-        log_to_file("No peers.conf file found");
-        return; 
+fn state_file() -> &'static str {
+    &config().state_file
+}
+
+fn delta_file() -> String {
+    config().delta_file()
+}
+
+fn outbox_file() -> String {
+    config().outbox_file()
+}
+
+fn peer_records_file() -> String {
+    config().peer_records_file()
+}
+
+// How state is read and written: the configured encoding, sealed with the
+// configured key if there is one
+fn codec() -> &'static Codec {
+    static CODEC: OnceLock<Codec> = OnceLock::new();
+    CODEC.get_or_init(|| {
+        let key = config().key_file.as_deref().map(|path| {
+            StateKey::load(path).unwrap_or_else(|e| {
+                eprintln!("ERROR: cannot read key {}: {}", path, e);
+                log_to_file(&format!("Cannot read key {}: {}", path, e));
+                std::process::exit(1);
+            })
+        });
+        Codec { encoding: config().encoding, key }
+    })
+}
+
+// This node's key for signing sync payloads, if one is configured
+fn node_key() -> Option<&'static NodeKey> {
+    static KEY: OnceLock<Option<NodeKey>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        config().signing_key.as_deref().map(|path| {
+            NodeKey::load(path).unwrap_or_else(|e| {
+                eprintln!("ERROR: cannot read signing key {}: {}", path, e);
+                log_to_file(&format!("Cannot read signing key {}: {}", path, e));
+                std::process::exit(1);
+            })
+        })
+    }).as_ref()
+}
+
+// Keys incoming payloads are checked against; without them payloads are
+// merged unchecked
+fn trusted_keys() -> Option<&'static TrustedKeys> {
+    static TRUSTED: OnceLock<Option<TrustedKeys>> = OnceLock::new();
+    TRUSTED.get_or_init(|| {
+        config().trusted_keys.as_deref().map(|path| {
+            TrustedKeys::load(path).unwrap_or_else(|e| {
+                eprintln!("ERROR: cannot read trusted keys {}: {}", path, e);
+                log_to_file(&format!("Cannot read trusted keys {}: {}", path, e));
+                std::process::exit(1);
+            })
+        })
+    }).as_ref()
+}
+
+// The key this node signs in to peers' listeners with
+fn sync_key() -> io::Result<&'static NodeKey> {
+    node_key().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::PermissionDenied, "no signing_key configured to sign in to peers with")
+    })
+}
+
+// A state or delta as sent to peers: encoded, and signed if we have a key
+fn encode_payload(payload: &MayaState) -> std::io::Result<Vec<u8>> {
+    let data = payload.encode(codec())?;
+    Ok(match node_key() {
+        Some(key) => key.sign(&payload.node_id, &data),
+        None => data,
+    })
+}
+
+// A payload from a peer. With trusted keys configured it must be signed
+// by the node it comes from.
+fn decode_payload(data: &[u8]) -> Result<MayaState, LoadError> {
+    let remote = match trusted_keys() {
+        Some(trusted) => trusted.decode(data, codec())?,
+        None => MayaState::decode(signing::unsigned(data), codec())?,
+    };
+    check_clock(remote)
+}
+
+// Refuse a peer's state whose clock runs too far ahead of ours
+fn check_clock(remote: MayaState) -> Result<MayaState, LoadError> {
+    let max_drift = Duration::from_secs(config().max_clock_drift);
+    remote.check_drift(now_millis(), max_drift).map_err(LoadError::Drift)?;
+    Ok(remote)
+}
+
+// A store of the configured `storage` kind at `path`
+fn storage_at(path: &str, codec: Codec) -> Box<dyn Storage> {
+    match config().storage {
+        Backend::File => Box::new(FileStorage::new(path, codec)),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => {
+            let db = maya_crdt::storage::SqliteStorage::open(path, codec.key);
+            Box::new(db.unwrap_or_else(|e| {
+                eprintln!("ERROR: cannot open {}: {}", path, e);
+                log_to_file(&format!("Cannot open {}: {}", path, e));
+                std::process::exit(1);
+            }))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => {
+            eprintln!("ERROR: storage = \"sqlite\" needs a build with the sqlite feature");
+            std::process::exit(1);
+        }
     }
-    
-    let peers = peers_content.unwrap();
+}
+
+// Where the state lives, per the `storage` setting
+fn storage() -> &'static dyn Storage {
+    static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+    STORAGE.get_or_init(|| storage_at(state_file(), codec().clone())).as_ref()
+}
+
+// Prefix an I/O error with what was being done, for one-shot commands to
+// print or the daemon to log
+fn context(what: String) -> impl FnOnce(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+// One-shot commands: report a failed read or write and give up loudly
+fn or_exit<T>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        log_to_file(&format!("ERROR: {}", e));
+        std::process::exit(1);
+    })
+}
+
+// Take the state file lock for a load/modify/save cycle
+fn lock_state() -> io::Result<StateLock> {
+    StateLock::acquire(state_file()).map_err(context(format!("cannot lock {}", state_file())))
+}
+
+// Load the state or give up loudly: carrying on with an empty state would
+// overwrite the attacker history on the next save
+fn load_state(node_id: &str) -> MayaState {
+    exit_on_load_error(storage().load(node_id))
+}
+
+// The state with only the attackers in `ips`, for a command that touches
+// no others; see `Storage::load_attackers`
+fn load_state_for(node_id: &str, ips: &[&str]) -> MayaState {
+    exit_on_load_error(storage().load_attackers(node_id, ips))
+}
+
+fn exit_on_load_error(result: Result<MayaState, LoadError>) -> MayaState {
+    result.unwrap_or_else(|e| {
+        eprintln!("ERROR: cannot load {}: {}", state_file(), e);
+        if matches!(e, LoadError::Corrupt { .. } | LoadError::Missing) {
+            eprintln!("Run `syslogd-helper recover` to restore the last good backup");
+        }
+        log_to_file(&format!("Cannot load {}: {}", state_file(), e));
+        std::process::exit(1);
+    })
+}
+
+// Daemon variant: nobody is watching stderr, so a corrupt or missing state
+// file is restored from the newest good backup instead of stopping. Any
+// other failure (a newer format, the wrong key, an I/O error) is no reason
+// to throw the state file away, so the daemon reports it and stops.
+fn load_state_or_recover(node_id: &str) -> Option<MayaState> {
+    match storage().load(node_id) {
+        Ok(state) => Some(state),
+        Err(e @ (LoadError::Corrupt { .. } | LoadError::Missing)) => {
+            log_to_file(&format!("CRITICAL: cannot load {}: {}", state_file(), e));
+            match storage().recover() {
+                Ok((state, backup)) => {
+                    log_to_file(&format!("CRITICAL: restored {} from {}", state_file(), backup));
+                    Some(state)
+                }
+                Err(e) => {
+                    log_to_file(&format!("CRITICAL: cannot recover {}: {}", state_file(), e));
+                    None
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("ERROR: cannot load {}: {}", state_file(), e);
+            log_to_file(&format!("CRITICAL: cannot load {}, stopping: {}", state_file(), e));
+            std::process::exit(1);
+        }
+    }
+}
+
+fn snapshot_state(state: &MayaState) -> io::Result<()> {
+    storage().snapshot(state).map_err(context(format!("cannot save {}", state_file())))
+}
+
+fn save_deltas(deltas: &DeltaBuffer) -> io::Result<()> {
+    deltas.save(&delta_file(), codec().key.as_ref()).map_err(context(format!("cannot save {}", delta_file())))
+}
+
+// Log an operation's delta
+fn commit_op(state: &MayaState, op: &str, delta: &MayaState) -> io::Result<()> {
+    storage().commit(state, op, delta).map_err(context(format!("cannot log {} to {}", op, state_file())))
+}
+
+// Log the delta produced by the operation and queue it for the daemon
+fn persist(state: &MayaState, op: &str, delta: MayaState) {
+    or_exit(commit_op(state, op, &delta));
+
+    let mut buffer = DeltaBuffer::load(&delta_file(), codec().key.as_ref());
+    buffer.push(delta);
+    or_exit(save_deltas(&buffer));
+}
+
+fn read_peers() -> Option<Vec<String>> {
+    let peers = std::fs::read_to_string(&config().peers_file).ok()?;
+    Some(peers.lines()
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect())
+}
+
+// Where other members reach our listener: `advertise`, else `listen` with
+// the hostname in place of an unspecified IP. None when not listening.
+fn advertise_addr() -> Option<String> {
+    if let Some(addr) = &config().advertise {
+        return Some(addr.clone());
+    }
+    if config().listen.is_empty() {
+        return None;
+    }
+    match config().listen.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => {
+            let host = hostname::get().ok()?.into_string().ok()?;
+            Some(format!("{}:{}", host, addr.port()))
+        }
+        _ => Some(config().listen.clone()),
+    }
+}
+
+// Everyone this node syncs with: peers.conf, plus in gossip mode every
+// member we have heard of. peers.conf entries are kept as written since
+// the delta buffer tracks peers by them.
+fn known_peers(state: &MayaState) -> Vec<String> {
+    let seeds = read_peers().unwrap_or_else(|| {
+        log_to_file(&format!("No peers file {} found", config().peers_file));
+        Vec::new()
+    });
+    let mut peers: Vec<(String, Vec<SocketAddr>)> = seeds.into_iter()
+        .map(|peer| {
+            let addrs = sync::resolve(&peer);
+            (peer, addrs)
+        })
+        .collect();
+    if config().sync_mode == SyncMode::Gossip {
+        for (_, addr) in state.peers() {
+            let addrs = sync::resolve(addr);
+            if !peers.iter().any(|(peer, resolved)| same_listener((peer, resolved), (addr, &addrs))) {
+                peers.push((addr.clone(), addrs));
+            }
+        }
+    }
+    if let Some(own) = advertise_addr() {
+        let addrs = sync::resolve(&own);
+        peers.retain(|(peer, resolved)| !same_listener((peer, resolved), (&own, &addrs)));
+    }
+    peers.into_iter().map(|(peer, _)| peer).collect()
+}
+
+// Whether two peer entries, with what they resolve to, reach the same
+// listener: written alike, or sharing an address, like a seed given as a
+// hostname and the IP its node announces
+fn same_listener(a: (&str, &[SocketAddr]), b: (&str, &[SocketAddr])) -> bool {
+    peer_addr(a.0) == peer_addr(b.0) || a.1.iter().any(|addr| b.1.contains(addr))
+}
+
+// Merge a peer's state or delta into ours and log it. Returns what
+// happened, for the CLI to print or the daemon to log.
+fn merge_remote(state: &mut MayaState, remote: &MayaState) -> io::Result<String> {
+    let origin = remote.node_id.clone();
+    let causality = state.version.compare(&remote.version);
+    let before_hash = state.hash();
+    // Merge even when up to date: it still tells us what the
+    // sender has seen, which tombstone compaction relies on.
+    state.merge(remote);
+    commit_op(state, "merge", remote)?;
+    let after_hash = state.hash();
+    Ok(if matches!(causality, Causality::After | Causality::Equal) {
+        format!("Already up to date with {}", origin)
+    } else if after_hash == before_hash {
+        format!("Merged {}: no new data ({})", origin, after_hash)
+    } else {
+        format!("Merge complete: {} -> {}", before_hash, after_hash)
+    })
+}
+
+// Push a payload to a peer's sync listener, falling back to scp/ssh if
+// configured. Returns the peer's node id and the version vector it now
+// has, if it sent them back (ssh does not), or why the push failed.
+fn push_to_peer(peer: &str, payload: &MayaState) -> Result<(Option<String>, Option<VersionVector>), String> {
+    let data = encode_payload(payload).map_err(|e| {
+        log_to_file(&format!("Cannot encode sync payload for {}: {}", peer, e));
+        format!("cannot encode payload: {}", e)
+    })?;
+
+    let error = match sync_key().and_then(|key| sync::push(&peer_addr(peer), &payload.node_id, key, data.clone())) {
+        Ok((hello, ack)) => {
+            log_to_file(&format!("Sync to {} ({}) successful", peer, hello.node_id));
+            return Ok((Some(hello.node_id), Some(ack.version)));
+        }
+        Err(e) => {
+            log_to_file(&format!("Sync to {} failed: {}", peer, e));
+            e.to_string()
+        }
+    };
+
+    if config().ssh_fallback && push_over_ssh(peer, &data) {
+        return Ok((None, None));
+    }
+    Err(error)
+}
+
+// Note a failed exchange with `peer` and log how long it is left alone
+fn peer_failed(records: &mut PeerRecords, peer: &str, error: &str) {
+    let interval = Duration::from_secs(config().sync_interval);
+    let backoff = records.failed(peer, now_millis(), error, interval);
+    let failures = records.get(peer).map_or(0, |record| record.failures);
+    log_to_file(&format!("Peer {} failed {} time(s) in a row, retrying in {}s", peer, failures, backoff.as_secs()));
+}
+
+fn push_over_ssh(peer: &str, data: &[u8]) -> bool {
+    if let Err(e) = write_atomic(&outbox_file(), data) {
+        log_to_file(&format!("Cannot write sync payload for {}: {}", peer, e));
+        return false;
+    }
+
+    // Copy payload to peer - suppress all output
+    let scp_result = Command::new("scp")
+        .arg("-o")
+        .arg("StrictHostKeyChecking=no")
+        .arg("-o")
+        .arg("ConnectTimeout=5")
+        .arg("-o")
+        .arg("LogLevel=QUIET")
+        .arg(outbox_file())
+        .arg(format!("root@{}:/tmp/maya.state", peer_host(peer)))
+        .output();
+
+    match scp_result {
+        Ok(output) if output.status.success() => {
+            log_to_file(&format!("SCP to {} successful", peer));
+        },
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            log_to_file(&format!("SCP failed to {}: {}", peer, stderr));
+            return false;
+        },
+        Err(e) => {
+            log_to_file(&format!("SCP command failed for {}: {}", peer, e));
+            return false;
+        }
+    }
+
+    // Trigger merge on peer - suppress all output
+    let merge_result = Command::new("ssh")
+        .arg("-o")
+        .arg("StrictHostKeyChecking=no")
+        .arg("-o")
+        .arg("ConnectTimeout=5")
+        .arg("-o")
+        .arg("LogLevel=QUIET")
+        .arg(format!("root@{}", peer_host(peer)))
+        .arg(format!(
+            "sudo /usr/local/bin/syslogd-helper merge {}/tmp/maya.state && sudo rm /tmp/maya.state",
+            if signing::is_signed(data) { "" } else { "--allow-unsigned " },
+        ))
+        .output();
+
+    match merge_result {
+        Ok(merge_output) if merge_output.status.success() => {
+            log_to_file(&format!("Merge on {} successful", peer));
+            true
+        },
+        Ok(merge_output) => {
+            let stderr = String::from_utf8_lossy(&merge_output.stderr);
+            log_to_file(&format!("Merge failed on {}: {}", peer, stderr));
+            false
+        },
+        Err(e) => {
+            log_to_file(&format!("Merge command failed for {}: {}", peer, e));
+            false
+        }
+    }
+}
+
+// What a peer confirmed receiving, applied to the delta buffer afterwards
+struct PeerAck {
+    peer: String,
+    seq: u64,
+    version: VersionVector,
+}
+
+// Push to `peers` from a snapshot, noting each outcome in `records`. Runs
+// without the state lock held, since a slow peer can take seconds.
+fn sync_with_peers(state: &MayaState, deltas: &DeltaBuffer, peers: &[String], records: &mut PeerRecords) -> Vec<PeerAck> {
+    let mut acks = Vec::new();
     let mut successful_syncs = 0;
     let mut failed_syncs = 0;
-    
-    for peer in peers.lines() {
-        let peer = peer.trim();
-        if peer.is_empty() { continue; }
-        
-        log_to_file(&format!("Attempting to sync with peer: {}", peer));
-        
-        // Copy state file to peer - suppress all output
-        let scp_result = Command::new("scp")
-            .arg("-o")
-            .arg("StrictHostKeyChecking=no")
-            .arg("-o")
-            .arg("ConnectTimeout=5")
-            .arg("-o")
-            .arg("LogLevel=QUIET")
-            .arg(state_file)
-            .arg(format!("root@{}:/tmp/maya.state", peer))
-            .output();
-        
-        match scp_result {
-            Ok(output) if output.status.success() => {
-                log_to_file(&format!("SCP to {} successful", peer));
-                
-                // Trigger merge on peer - suppress all output
-                let merge_result = Command::new("ssh")
-                    .arg("-o")
-                    .arg("StrictHostKeyChecking=no")
-                    .arg("-o")
-                    .arg("ConnectTimeout=5")
-                    .arg("-o")
-                    .arg("LogLevel=QUIET")
-                    .arg(format!("root@{}", peer))
-                    .arg("sudo /usr/local/bin/syslogd-helper merge /tmp/maya.state && sudo rm /tmp/maya.state")
-                    .output();
-                
-                match merge_result {
-                    Ok(merge_output) if merge_output.status.success() => {
-                        log_to_file(&format!("Merge on {} successful", peer));
-                        successful_syncs += 1;
-                    },
-                    Ok(merge_output) => {
-                        let stderr = String::from_utf8_lossy(&merge_output.stderr);
-                        log_to_file(&format!("Merge failed on {}: {}", peer, stderr));
-                        failed_syncs += 1;
-                    },
-                    Err(e) => {
-                        log_to_file(&format!("Merge command failed for {}: {}", peer, e));
-                        failed_syncs += 1;
-                    }
-                }
-            },
-            Ok(output) => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                log_to_file(&format!("SCP failed to {}: {}", peer, stderr));
-                failed_syncs += 1;
-            },
+
+    for peer in peers {
+        match deltas.peer_causality(peer, &state.version) {
+            Some(Causality::Before | Causality::Equal) => {
+                // Peer already has every update we know about
+                acks.push(PeerAck { peer: peer.clone(), seq: deltas.next_seq, version: VersionVector::new() });
+                continue;
+            }
+            Some(_) => {
+                let missing = state.version.missing_from(&deltas.peer_versions[peer]);
+                log_to_file(&format!("Peer {} is behind on updates from: {}", peer, missing.join(", ")));
+            }
+            None => {}
+        }
+
+        // Ship only what the peer has not acknowledged; fall back to the
+        // full state for new peers or when its deltas were pruned.
+        let (payload, seq, kind) = match deltas.pending_for(peer) {
+            Pending::UpToDate => continue,
+            Pending::Delta(delta, seq) => (*delta, seq, "delta"),
+            Pending::Full(seq) => (state.clone(), seq, "full state"),
+        };
+
+        log_to_file(&format!("Attempting to sync {} with peer: {}", kind, peer));
+
+        match push_to_peer(peer, &payload) {
+            Ok((node, version)) => {
+                records.succeeded(peer, now_millis(), node.as_deref(), version.as_ref());
+                acks.push(PeerAck { peer: peer.clone(), seq, version: version.unwrap_or_default() });
+                successful_syncs += 1;
+            }
             Err(e) => {
-                log_to_file(&format!("SCP command failed for {}: {}", peer, e));
+                peer_failed(records, peer, &e);
                 failed_syncs += 1;
             }
         }
     }
-    
+
+    let _ = std::fs::remove_file(outbox_file());
+
     log_to_file(&format!("Sync cycle complete: {} successful, {} failed", successful_syncs, failed_syncs));
+    acks
 }
 
 fn detect_attacker_id() -> String {
     // Try SSH_CONNECTION first
-    if let Ok(conn) = std::env::var("SSH_CONNECTION") {
-        if let Some(ip) = conn.split_whitespace().next() {
-            return ip.to_string();
-        }
+    if let Ok(conn) = std::env::var("SSH_CONNECTION")
+        && let Some(ip) = conn.split_whitespace().next() {
+        return ip.to_string();
     }
     
     // Try SSH_CLIENT
-    if let Ok(client) = std::env::var("SSH_CLIENT") {
-        if let Some(ip) = client.split_whitespace().next() {
-            return ip.to_string();
-        }
+    if let Ok(client) = std::env::var("SSH_CLIENT")
+        && let Some(ip) = client.split_whitespace().next() {
+        return ip.to_string();
     }
     
     // Try to get IP from auth.log as fallback
-    if let Ok(log) = std::fs::read_to_string("/var/log/auth.log") {
+    if let Ok(log) = std::fs::read_to_string(&config().auth_log) {
         for line in log.lines().rev().take(20) {
             if line.contains("Accepted") {
                 let parts: Vec<&str> = line.split_whitespace().collect();
@@ -142,95 +518,383 @@ fn detect_attacker_id() -> String {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let node_id = hostname::get()
-        .map(|h| h.to_string_lossy().to_string())
-        .unwrap_or_else(|_| "unknown".to_string());
+    let mut args: Vec<String> = env::args().collect();
+    let resolved = Config::resolve(&mut args, |key| env::var(key).ok()).unwrap_or_else(|e| {
+        eprintln!("ERROR: bad configuration: {}", e);
+        std::process::exit(1);
+    });
+    let _ = CONFIG.set(resolved);
+    let node_id = config().node_id.clone().unwrap_or_else(|| {
+        hostname::get()
+            .map(|h| h.to_string_lossy().to_string())
+            .unwrap_or_else(|_| "unknown".to_string())
+    });
     
-    let mut state = MayaState::load(STATE_FILE, &node_id);
+    // Commands that load/modify/save hold the lock for the whole cycle so
+    // concurrent invocations cannot lose each other's updates
+    let command = args.get(1).map(|s| s.as_str());
+    let _lock = matches!(
+        command,
+        Some("visit" | "action" | "move" | "authfail" | "cred" | "uncred"
+            | "session" | "session-close" | "forget" | "merge" | "compact" | "recover" | "convert" | "encrypt")
+    ).then(|| or_exit(lock_state()));
+
+    if command == Some("keygen") {
+        let path = args.get(2).cloned()
+            .or_else(|| config().key_file.clone())
+            .unwrap_or_else(|| DEFAULT_KEY_FILE.to_string());
+        match StateKey::generate().save(&path) {
+            Ok(()) => println!("Wrote new key to {} (copy it to every peer, then set key_file in {})", path, CONFIG_FILE),
+            Err(e) => {
+                eprintln!("ERROR: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if command == Some("keygen-signing") {
+        let path = args.get(2).cloned()
+            .or_else(|| config().signing_key.clone())
+            .unwrap_or_else(|| DEFAULT_SIGNING_KEY_FILE.to_string());
+        let key = NodeKey::generate();
+        match key.save(&path) {
+            Ok(()) => {
+                println!("Wrote new signing key to {} (set signing_key in {})", path, CONFIG_FILE);
+                println!("Add this line to the trusted_keys file on every peer:");
+                println!("{} {}", node_id, key.public_hex());
+            }
+            Err(e) => {
+                eprintln!("ERROR: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    match args.get(1).map(|s| s.as_str()) {
+    if command == Some("convert") {
+        let encoding = match args.get(2).map(|e| e.parse::<Encoding>()) {
+            Some(Ok(encoding)) => encoding,
+            Some(Err(e)) => {
+                eprintln!("ERROR: {}", e);
+                std::process::exit(1);
+            }
+            None => {
+                println!("Usage: syslogd-helper convert <json|binary> [input] [output|-]");
+                return;
+            }
+        };
+        let input = args.get(3).map(String::as_str).unwrap_or(state_file());
+        let output = args.get(4).map(String::as_str).unwrap_or(input);
+        let state = storage_at(input, codec().clone()).load(&node_id).unwrap_or_else(|e| {
+            eprintln!("ERROR: cannot load {}: {}", input, e);
+            std::process::exit(1);
+        });
+        // `-` prints the decrypted state, for debugging
+        if output == "-" {
+            match state.encode(&Codec::from(encoding)) {
+                Ok(data) => { let _ = std::io::stdout().write_all(&data); }
+                Err(e) => eprintln!("ERROR: {}", e),
+            }
+            return;
+        }
+        let codec = Codec { encoding, key: codec().key.clone() };
+        or_exit(storage_at(output, codec).snapshot(&state).map_err(context(format!("cannot write {}", output))));
+        println!("Wrote {} as {}", output, encoding);
+        return;
+    }
+
+    if command == Some("recover") {
+        match storage().load(&node_id) {
+            Ok(_) => println!("{} is readable, nothing to recover", state_file()),
+            Err(e) => {
+                println!("{}: {}", state_file(), e);
+                match storage().recover() {
+                    Ok((state, backup)) => {
+                        log_to_file(&format!("Restored {} from {}", state_file(), backup));
+                        println!("Restored from {} ({} attackers)", backup, state.attackers.len());
+                    }
+                    Err(e) => {
+                        eprintln!("ERROR: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        return;
+    }
+
+    // Turning encryption on: a key refuses plaintext, so what was written
+    // before it was set is sealed once, here
+    if command == Some("encrypt") {
+        let Some(key) = &codec().key else {
+            eprintln!("ERROR: no key_file configured; run `syslogd-helper keygen` and set key_file in {}", CONFIG_FILE);
+            std::process::exit(1);
+        };
+        let mut sealed = or_exit(storage().seal_plaintext());
+        for file in [delta_file(), peer_records_file()] {
+            if or_exit(maya_crdt::seal_file(&file, key).map_err(context(format!("cannot seal {}", file)))) {
+                sealed.push(file);
+            }
+        }
+        for file in &sealed {
+            log_to_file(&format!("Sealed {}", file));
+            println!("Sealed {}", file);
+        }
+        if sealed.is_empty() {
+            println!("Nothing left in plaintext");
+        }
+        return;
+    }
+
+    // One attacker's record, read without loading the others where the
+    // storage allows it
+    if command == Some("attacker") {
+        let Some(ip) = args.get(2) else {
+            println!("Usage: syslogd-helper attacker <ip>");
+            return;
+        };
+        match storage().attacker(ip) {
+            Ok(Some(attacker)) => match serde_json::to_string_pretty(&attacker) {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("ERROR: {}", e),
+            },
+            Ok(None) => println!("No attacker {}", ip),
+            Err(e) => {
+                eprintln!("ERROR: cannot load {}: {}", state_file(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Commands that touch one attacker, or none, leave the others unread
+    // where the storage allows it
+    let explicit = |n: usize| args.len() > n;
+    let ip = match command {
+        Some("visit" | "move") => Some(if explicit(3) { args[2].clone() } else { detect_attacker_id() }),
+        Some("action") => Some(if explicit(4) { args[2].clone() } else { detect_attacker_id() }),
+        Some("authfail") => Some(args.get(2).cloned().unwrap_or_else(detect_attacker_id)),
+        _ => None,
+    };
+    let mut state = match (command, &ip) {
+        (_, Some(ip)) => load_state_for(&node_id, &[ip]),
+        (Some("cred" | "uncred" | "session" | "session-close"), None) => load_state_for(&node_id, &[]),
+        _ => load_state(&node_id),
+    };
+    // Detect once: the state was loaded for that attacker only
+    let detected = || ip.clone().unwrap_or_else(detect_attacker_id);
+
+    match command {
         Some("visit") => {
             if let (Some(attacker_ip), Some(decoy)) = (args.get(2), args.get(3)) {
-                state.observe_visit(attacker_ip, decoy);
-                state.save(STATE_FILE);
+                let delta = state.observe_visit(attacker_ip, decoy);
+                persist(&state, "visit", delta);
                 // Only print to stdout for direct commands, not for daemon
                 println!("Recorded visit: attacker={} decoy={}", attacker_ip, decoy);
             } else if let Some(decoy) = args.get(2) {
-                let attacker = detect_attacker_id();
+                let attacker = detected();
                 eprintln!("WARNING: Using auto-detected attacker IP: {}", attacker);
-                state.observe_visit(&attacker, decoy);
-                state.save(STATE_FILE);
+                let delta = state.observe_visit(&attacker, decoy);
+                persist(&state, "visit", delta);
             }
         }
         
         Some("action") => {
             if let (Some(attacker_ip), Some(decoy), Some(action)) = (args.get(2), args.get(3), args.get(4)) {
-                state.record_action(attacker_ip, decoy, action);
-                state.save(STATE_FILE);
+                let delta = state.record_action(attacker_ip, decoy, action);
+                persist(&state, "action", delta);
                 println!("Recorded action: attacker={} decoy={} action={}", attacker_ip, decoy, action);
             } else if let (Some(decoy), Some(action)) = (args.get(2), args.get(3)) {
-                let attacker = detect_attacker_id();
+                let attacker = detected();
                 eprintln!("WARNING: Using auto-detected attacker IP: {}", attacker);
-                state.record_action(&attacker, decoy, action);
-                state.save(STATE_FILE);
+                let delta = state.record_action(&attacker, decoy, action);
+                persist(&state, "action", delta);
             }
         }
         
         Some("move") => {
             if let (Some(attacker_ip), Some(location)) = (args.get(2), args.get(3)) {
-                state.update_location(attacker_ip, location);
-                state.save(STATE_FILE);
+                let delta = state.update_location(attacker_ip, location);
+                persist(&state, "move", delta);
                 println!("Recorded move: attacker={} location={}", attacker_ip, location);
             } else if let Some(location) = args.get(2) {
-                let attacker = detect_attacker_id();
+                let attacker = detected();
+                eprintln!("WARNING: Using auto-detected attacker IP: {}", attacker);
+                let delta = state.update_location(&attacker, location);
+                persist(&state, "move", delta);
+            }
+        }
+        
+        Some("authfail") => {
+            if let Some(attacker_ip) = args.get(2) {
+                let delta = state.record_auth_failure(attacker_ip);
+                persist(&state, "authfail", delta);
+                println!("Recorded auth failure: attacker={}", attacker_ip);
+            } else {
+                let attacker = detected();
                 eprintln!("WARNING: Using auto-detected attacker IP: {}", attacker);
-                state.update_location(&attacker, location);
-                state.save(STATE_FILE);
+                let delta = state.record_auth_failure(&attacker);
+                persist(&state, "authfail", delta);
             }
         }
         
         Some("cred") => {
             if let Some(cred) = args.get(2) {
-                state.add_cred(cred);
-                state.save(STATE_FILE);
+                let delta = state.add_cred(cred);
+                persist(&state, "cred", delta);
                 println!("Recorded credential: {}", cred);
             }
         }
         
+        Some("uncred") => {
+            if let Some(cred) = args.get(2) {
+                if !state.stolen_creds.elements().contains(cred) {
+                    println!("Credential not recorded: {}", cred);
+                    return;
+                }
+                let delta = state.remove_cred(cred);
+                persist(&state, "uncred", delta);
+                println!("Removed credential: {}", cred);
+            }
+        }
+        
         Some("session") => {
             if let (Some(host), Some(session)) = (args.get(2), args.get(3)) {
-                state.add_session(host, session);
-                state.save(STATE_FILE);
-                println!("Recorded session: {} -> {}", host, session);
+                let ttl = match args.get(4).map(|secs| secs.parse::<u64>()) {
+                    Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                    Some(Err(_)) => {
+                        eprintln!("ERROR: session TTL must be whole seconds, got {:?}", args[4]);
+                        eprintln!("Usage: syslogd-helper session <host> <session> [ttl-secs]");
+                        std::process::exit(1);
+                    }
+                    None => None,
+                };
+                let delta = state.add_session(host, session, ttl);
+                persist(&state, "session", delta);
+                match ttl {
+                    Some(ttl) => println!("Recorded session: {} -> {} (expires in {}s)", host, session, ttl.as_secs()),
+                    None => println!("Recorded session: {} -> {}", host, session),
+                }
+            }
+        }
+        
+        Some("session-close") => {
+            if let Some(host) = args.get(2) {
+                if state.active_sessions.get(host).is_none() {
+                    println!("No open session on {}", host);
+                    return;
+                }
+                let delta = state.close_session(host);
+                persist(&state, "session-close", delta);
+                println!("Closed session on {}", host);
             }
         }
         
         Some("merge") => {
-            if let Some(path) = args.get(2) {
-                let remote = MayaState::load(path, &node_id);
-                let before_hash = state.hash();
-                state.merge(remote);
-                state.save(STATE_FILE);
-                let after_hash = state.hash();
-                println!("Merge complete: {} -> {}", before_hash, after_hash);
+            // Unsigned data could come from anyone who can write the file,
+            // e.g. /tmp/maya.state, so it is only merged on request and
+            // never by a node that has keys
+            let allow_unsigned = args.get(2).map(|s| s.as_str()) == Some("--allow-unsigned");
+            let keyed = config().signing_key.is_some() || config().trusted_keys.is_some();
+            if let Some(path) = args.get(if allow_unsigned { 3 } else { 2 }) {
+                let remote = match std::fs::read(path) {
+                    Ok(data) if trusted_keys().is_some() || signing::is_signed(&data) => decode_payload(&data),
+                    _ if keyed => Err(LoadError::Signature(signing::SignatureError::Unsigned)),
+                    _ if !allow_unsigned => Err(LoadError::Io(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "payload is not signed (merge --allow-unsigned <file> to merge it anyway)",
+                    ))),
+                    // Opening a database that is not there would create it
+                    Err(e) if config().storage == Backend::Sqlite => Err(LoadError::Io(e)),
+                    _ => {
+                        log_to_file(&format!("WARNING: merging unsigned {} (--allow-unsigned)", path));
+                        storage_at(path, codec().clone()).load(&node_id).and_then(check_clock)
+                    }
+                };
+                let remote = remote.unwrap_or_else(|e| {
+                    eprintln!("ERROR: cannot load {}: {}", path, e);
+                    log_to_file(&format!("Rejected merge payload {}: {}", path, e));
+                    std::process::exit(1);
+                });
+                println!("{}", or_exit(merge_remote(&mut state, &remote)));
+            } else {
+                println!("Usage: syslogd-helper merge [--allow-unsigned] <file>");
+            }
+        }
+
+        Some("compact") => {
+            // Every peer must be a replica whose knowledge we have, matched
+            // by node id: the id it gave in its last handshake, or the
+            // member announced at its address
+            let records = PeerRecords::load(&peer_records_file(), codec().key.as_ref());
+            let unknown: Vec<String> = known_peers(&state)
+                .into_iter()
+                .filter(|peer| {
+                    let node = records.get(peer).and_then(|record| record.node_id.clone()).or_else(|| {
+                        state.peers().find(|(_, addr)| peer_addr(addr) == peer_addr(peer)).map(|(node, _)| node.clone())
+                    });
+                    node.is_none_or(|node| !state.knowledge.contains_key(&node))
+                })
+                .collect();
+            let force = args.get(2).map(|s| s.as_str()) == Some("--force");
+            if !unknown.is_empty() && !force {
+                println!(
+                    "Refusing to compact: {} not reported what they have seen (use --force to override)",
+                    if unknown.len() == 1 { format!("peer {} has", unknown[0]) } else { format!("peers {} have", join_and(&unknown)) },
+                );
+                return;
+            }
+            // Dropping tombstones is not a delta, so it goes straight to a
+            // snapshot. Buffered deltas and logged ops still carry the add
+            // tags it dropped; strip them so they cannot be replayed.
+            let dropped = state.compact();
+            or_exit(snapshot_state(&state));
+            or_exit(storage().strip_compacted(&state).map_err(context("cannot strip compacted tags from the op log".into())));
+            let mut deltas = DeltaBuffer::load(&delta_file(), codec().key.as_ref());
+            for delta in deltas.deltas.values_mut() {
+                state.strip_compacted(delta);
             }
+            or_exit(save_deltas(&deltas));
+            println!("Compacted {} tombstones ({} remaining)", dropped, state.stolen_creds.tombstones());
         }
         
         Some("daemon") => { 
             // Redirect all output to log file
-            run_daemon(state); 
+            run_daemon(&state.node_id); 
         }
         
+        Some("export") => {
+            let Some(output) = args.get(2) else {
+                println!("Usage: syslogd-helper export <output>");
+                return;
+            };
+            if let Err(e) = encode_payload(&state).and_then(|data| write_atomic(output, &data)) {
+                eprintln!("ERROR: cannot write {}: {}", output, e);
+                std::process::exit(1);
+            }
+            match node_key() {
+                Some(_) => println!("Wrote signed state to {}", output),
+                None => println!("Wrote unsigned state to {} (no signing_key configured)", output),
+            }
+        }
+
         Some("hash") => println!("{}", state.hash()),
+
+        Some("digest") => match serde_json::to_string_pretty(&state.digest()) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("ERROR: {}", e),
+        },
         
         Some("stats") => {
             println!("===============================");
             println!("Node: {}", state.node_id);
             println!("Lamport Clock: {}", state.clock.counter);
+            println!("Version Vector: {:?}", state.version.entries);
             println!("Attackers: {}", state.attackers.len());
             println!("Credentials: {}", state.stolen_creds.elements().len());
-            println!("Sessions: {}", state.active_sessions.entries.len());
+            println!("Tombstones: {}", state.stolen_creds.tombstones());
+            println!("Sessions: {}", state.live_sessions().count());
             let total_decoys: usize = state.attackers.values().map(|a| a.visited_decoys.elements.len()).sum();
             println!("Decoys visited: {}", total_decoys);
             println!("State hash: {}", state.hash());
@@ -239,53 +903,127 @@ fn main() {
             if !state.attackers.is_empty() {
                 println!("\nTracked Attackers:");
                 for (ip, attacker) in &state.attackers {
-                    println!("  - IP: {} | Visited: {} decoys", 
+                    println!("  - IP: {} | Visited: {} decoys | Visits: {} | Actions: {} | Auth failures: {}", 
                         ip, 
-                        attacker.visited_decoys.elements.len()
+                        attacker.visited_decoys.elements.len(),
+                        attacker.total_visits(),
+                        attacker.action_count.value(),
+                        attacker.auth_failures.value()
                     );
+                    if let Some(locations) = attacker.location.conflicts() {
+                        println!("    ⚠️  attacker seen simultaneously on {}", join_and(&locations));
+                    }
+                }
+            }
+
+            let deltas = DeltaBuffer::load(&delta_file(), codec().key.as_ref());
+            if !deltas.peer_versions.is_empty() {
+                println!("\nPeer Versions:");
+                for (peer, known) in deltas.peer_versions.iter().filter(|(_, known)| !known.entries.is_empty()) {
+                    let missing = state.version.missing_from(known);
+                    if missing.is_empty() {
+                        println!("  - {} | up to date", peer);
+                    } else {
+                        println!("  - {} | behind on: {}", peer, missing.join(", "));
+                    }
                 }
             }
         }
         
+        Some("oplog") => {
+            let ops = read_oplog(&oplog_path(state_file()), codec().key.as_ref());
+            println!("{} operations since the last snapshot", ops.len());
+            for logged in ops {
+                let at = chrono::DateTime::from_timestamp_millis(logged.at as i64)
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default();
+                println!("  [{}] {} from {}", at, logged.op, logged.delta.node_id);
+            }
+        }
+
+        // `--json` is the decrypted, decoded state with the op log applied,
+        // whatever the encoding, key or backend; the dashboard reads this
+        Some("show") if args.get(2).map(String::as_str) == Some("--json") => {
+            match serde_json::to_string(&state) {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    eprintln!("ERROR: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
         Some("show") => { 
             state.print_summary(); 
         }
         
+        Some("members") => {
+            for (member, addr, _) in state.members.iter() {
+                let own = if *member == state.node_id { " (this node)" } else { "" };
+                println!("{} {}{}", member, addr, own);
+            }
+        }
+
+        Some("forget") => {
+            if let Some(member) = args.get(2) {
+                if state.members.get(member).is_none() {
+                    println!("{} is not a member", member);
+                    return;
+                }
+                let delta = state.forget_member(member);
+                persist(&state, "forget", delta);
+                println!("Forgot member {}", member);
+            }
+        }
+
         Some("check-peers") => {
-            if let Ok(peers) = std::fs::read_to_string("/etc/syslogd-helper/peers.conf") {
-                println!("Peers configured:");
-                for peer in peers.lines() {
-                    let peer = peer.trim();
-                    if !peer.is_empty() {
-                        // Test connectivity
-                        let test = Command::new("ssh")
-                            .arg("-o")
-                            .arg("ConnectTimeout=2")
-                            .arg("-o")
-                            .arg("StrictHostKeyChecking=no")
-                            .arg("-o")
-                            .arg("LogLevel=QUIET")
-                            .arg(format!("root@{}", peer))
-                            .arg("echo 'OK' 2>/dev/null")
-                            .output();
-                        
-                        match test {
-                            Ok(output) if output.status.success() => {
-                                println!("  ✅ {} - reachable", peer);
-                            },
-                            _ => {
-                                println!("  ❌ {} - unreachable", peer);
-                            }
-                        }
+            // What the daemon recorded, not a fresh probe: a peer that only
+            // fails now and then shows up here
+            let records = PeerRecords::load(&peer_records_file(), codec().key.as_ref());
+            let peers = known_peers(&state);
+            if peers.is_empty() {
+                println!("No peers configured in {}", config().peers_file);
+                return;
+            }
+
+            let at = |millis: Option<u64>| millis
+                .and_then(|millis| chrono::DateTime::from_timestamp_millis(millis as i64))
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "never".to_string());
+            let now = now_millis();
+            println!("{:<24} {:<16} {:<19} {:>8} {:<10} {:<24} LAST ERROR", "PEER", "NODE", "LAST SUCCESS (UTC)", "FAILURES", "NEXT TRY", "VERSION");
+            for peer in &peers {
+                let record = records.get(peer).cloned().unwrap_or_default();
+                let next = if record.retry_at > now {
+                    format!("in {}s", (record.retry_at - now).div_ceil(1000))
+                } else {
+                    "now".to_string()
+                };
+                // A peer always has its own updates, even ones it made
+                // after the version we recorded. One only reached over
+                // ssh never told us what it has.
+                let version = if record.last_success.is_none() || record.version.entries.is_empty() {
+                    "unknown".to_string()
+                } else {
+                    let mut missing = state.version.missing_from(&record.version);
+                    missing.retain(|node| record.node_id.as_ref() != Some(node));
+                    if missing.is_empty() {
+                        "up to date".to_string()
+                    } else {
+                        format!("behind on {}", missing.join(","))
                     }
-                }
-            } else {
-                println!("No peers.conf file found");
+                };
+                let error = match (&record.last_error, record.last_failure) {
+                    (Some(error), failed) => format!("{} ({})", error, at(failed)),
+                    (None, _) => "-".to_string(),
+                };
+                let node = record.node_id.as_deref().unwrap_or("?");
+                println!("{:<24} {:<16} {:<19} {:>8} {:<10} {:<24} {}", peer, node, at(record.last_success), record.failures, next, version, error);
             }
         }
         
         None => { 
-            println!("Usage: syslogd-helper <visit|action|move|cred|session|merge|daemon|hash|stats|show|check-peers>"); 
+            println!("Usage: syslogd-helper [--config FILE] [--state-file FILE] [--log-file FILE] [--peers-file FILE] [--auth-log FILE] [--node-id ID] [--sync-interval SECS] [--max-clock-drift SECS] [--key-file FILE] [--encoding json|binary] [--storage file|sqlite] [--listen ADDR] [--anti-entropy off|pull|push-pull] [--sync-mode mesh|gossip] [--fanout N] [--advertise ADDR] [--ssh-fallback true|false] [--signing-key FILE] [--trusted-keys FILE] <visit|action|move|authfail|cred|uncred|session|session-close|members|forget|merge|compact|recover|convert|encrypt|keygen|keygen-signing|export|attacker|daemon|hash|digest|stats|oplog|show|check-peers>"); 
         }
         
         _ => { 
@@ -294,49 +1032,267 @@ fn main() {
     }
 }
 
-fn run_daemon(mut state: MayaState) {
-    let mut last_hash = hash_file(STATE_FILE);
+// This node as its sync listener's peers see it
+struct DaemonReplica {
+    node_id: String,
+}
+
+impl Replica for DaemonReplica {
+    fn merge(&self, from: &Hello, payload: &[u8]) -> Result<VersionVector, String> {
+        let remote = decode_payload(payload).map_err(|e| {
+            log_to_file(&format!("Rejected sync payload from {}: {}", from.node_id, e));
+            e.to_string()
+        })?;
+        // The peer sees only that we failed; the details go to our log
+        let _lock = lock_state().map_err(|e| log_to_file(&e.to_string())).or(Err("state unavailable"))?;
+        let mut state = load_state_or_recover(&self.node_id).ok_or("state unavailable")?;
+        let merged = merge_remote(&mut state, &remote).map_err(|e| {
+            log_to_file(&format!("Cannot merge sync payload from {}: {}", from.node_id, e));
+            "cannot save the merge"
+        })?;
+        log_to_file(&merged);
+        Ok(state.version)
+    }
+
+    fn pull(&self, from: &Hello, remote: &StateDigest) -> Result<(StateDigest, Vec<u8>), String> {
+        let state = {
+            let _lock = lock_state().map_err(|e| log_to_file(&e.to_string())).or(Err("state unavailable"))?;
+            load_state_or_recover(&self.node_id).ok_or("state unavailable")?
+        };
+        let digest = state.digest();
+        let diff = digest.diff(remote);
+        if !diff.is_empty() {
+            log_to_file(&format!("Serving pull from {}: {} attackers differ", from.node_id, diff.attackers.len()));
+        }
+        let payload = encode_payload(&state.extract(&diff)).map_err(|e| e.to_string())?;
+        Ok((digest, payload))
+    }
+}
+
+// One anti-entropy round with `peer`: compare digests, pull what differs
+// and, for push-pull, push back what the peer lacks. Covers updates
+// pushes missed, e.g. while a node was down. Runs without the lock on a
+// snapshot; returns what was pulled, to merge under the lock.
+fn anti_entropy_round(state: &MayaState, peer: &str, records: &mut PeerRecords) -> Option<MayaState> {
+    if config().anti_entropy == AntiEntropy::Off {
+        return None;
+    }
+    let digest = state.digest();
+    let push_back = config().anti_entropy == AntiEntropy::PushPull;
+
+    let round = sync_key().and_then(|key| {
+        sync::push_pull(&peer_addr(peer), &state.node_id, key, &digest, |pulled| {
+            let diff = digest.diff(&pulled.digest);
+            if !push_back || diff.is_empty() {
+                return Ok(None);
+            }
+            encode_payload(&state.extract(&diff)).map(Some)
+        })
+    });
+    if let Ok((pulled, ack)) = &round {
+        records.succeeded(peer, now_millis(), Some(&pulled.hello.node_id), ack.as_ref().map(|ack| &ack.version));
+    }
+    match round {
+        Ok((pulled, _)) if pulled.digest.root == digest.root => None,
+        Ok((pulled, ack)) => {
+            let pushed = if ack.is_some() { ", pushed back what it lacked" } else { "" };
+            log_to_file(&format!("Anti-entropy with {}: pulled {} bytes{}", peer, pulled.payload.len(), pushed));
+            decode_payload(&pulled.payload)
+                .map_err(|e| log_to_file(&format!("Rejected sync payload from {}: {}", pulled.hello.node_id, e)))
+                .ok()
+        }
+        Err(e) => {
+            log_to_file(&format!("Anti-entropy with {} failed: {}", peer, e));
+            peer_failed(records, peer, &e.to_string());
+            None
+        }
+    }
+}
+
+// Steps 3 to 6 of a daemon cycle, run under the state lock. None if the
+// state cannot be loaded. On an error the rest of the cycle is skipped:
+// what did not reach the op log is pulled, pushed or detected again next
+// cycle, and what did is still queued for peers.
+fn update_state(node_id: &str, pulled: Option<MayaState>, acks: Vec<PeerAck>, peers: &[String], records: &mut PeerRecords) -> io::Result<Option<MayaState>> {
+    // 🔥 3. Reload: CLI commands and peer merges may have written while
+    //       we were syncing
+    let Some(mut state) = load_state_or_recover(node_id) else {
+        return Ok(None);
+    };
+    if let Some(remote) = pulled {
+        log_to_file(&merge_remote(&mut state, &remote)?);
+    }
+    let mut deltas = DeltaBuffer::load(&delta_file(), codec().key.as_ref());
+    for ack in acks {
+        deltas.ack(&ack.peer, ack.seq);
+        deltas.observe_peer(&ack.peer, &ack.version);
+    }
+    deltas.prune(peers);
+    records.retain(peers);
+    if let Err(e) = records.save(&peer_records_file(), codec().key.as_ref()) {
+        log_to_file(&format!("Cannot save peer records: {}", e));
+    }
+
+    let recorded = record_local_changes(&mut state, &mut deltas);
+
+    // 🔥 6. Snapshot the ops logged since the last cycle so the state file
+    //       stays current, then persist the delta buffer, also after a
+    //       failed step so that what was logged still reaches peers
+    if recorded.is_ok() && storage().has_unsnapshotted_ops() {
+        snapshot_state(&state)?;
+    }
+    save_deltas(&deltas)?;
+    recorded.map(|()| Some(state))
+}
+
+// Log and queue what this node noticed itself: its membership, new
+// attackers in auth.log and expired sessions
+fn record_local_changes(state: &mut MayaState, deltas: &mut DeltaBuffer) -> io::Result<()> {
+    // Announce where we listen, again if the address changed or another
+    // node forgot us
+    if let Some(addr) = advertise_addr()
+        && state.members.get(&state.node_id) != Some(&addr)
+    {
+        let delta = state.announce(&addr);
+        commit_op(state, "announce", &delta)?;
+        deltas.push(delta);
+        log_to_file(&format!("Announced membership at {}", addr));
+    }
+
+    // 🔥 4. Process SSH log (only for new attackers)
+    if let Ok(log) = std::fs::read_to_string(&config().auth_log) {
+        for line in log.lines() {
+            if line.contains("Accepted password") || line.contains("Accepted publickey") {
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                for part in &parts {
+                    if part.contains('.') && part.parse::<std::net::Ipv4Addr>().is_ok() {
+                        if !state.attackers.contains_key(*part) {
+                            let visit = state.observe_visit(part, "ssh");
+                            commit_op(state, "visit", &visit)?;
+                            deltas.push(visit);
+                            let location = state.update_location(part, "ssh");
+                            commit_op(state, "move", &location)?;
+                            deltas.push(location);
+                            log_to_file(&format!("New attacker detected via SSH: {}", part));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // 🔥 5. Close sessions whose TTL ran out
+    if let Some(delta) = state.expire_sessions() {
+        log_to_file("Expired stale sessions");
+        commit_op(state, "expire", &delta)?;
+        deltas.push(delta);
+    }
+    Ok(())
+}
+
+fn run_daemon(node_id: &str) {
     let mut cycle_count = 0;
+    let mut last_hash = String::new();
+    let mut records = PeerRecords::load(&peer_records_file(), codec().key.as_ref());
+
+    log_to_file(&format!("Starting CRDT daemon on {}", node_id));
+    // Read the keys now: an unreadable key file should stop the daemon
+    // here, not later from a sync thread
+    let _ = (node_key(), trusted_keys());
+    // Peers only accept syncs from nodes that sign in, so a node listing
+    // peers without a key, say one upgraded from before sign-in, would
+    // fail every push without the ssh fallback
+    if node_key().is_none() && !config().ssh_fallback && read_peers().is_some_and(|peers| !peers.is_empty()) {
+        let msg = format!(
+            "{} lists peers but no transport reaches them: set signing_key and add this node's key to their trusted_keys, or set ssh_fallback = true",
+            config().peers_file
+        );
+        eprintln!("ERROR: {}", msg);
+        log_to_file(&format!("CRITICAL: {}", msg));
+        std::process::exit(1);
+    }
 
-    log_to_file(&format!("Starting CRDT daemon on {}", state.node_id));
+    // Accept pushes and pulls from trusted peers in the background; the
+    // config is refused if it sets listen without trusted_keys
+    if !config().listen.is_empty() {
+        match TcpListener::bind(&config().listen) {
+            Ok(listener) => {
+                let trusted = Arc::new(trusted_keys().cloned().unwrap_or_default());
+                let replica = Arc::new(DaemonReplica { node_id: node_id.to_string() });
+                sync::spawn_listener(listener, node_id.to_string(), trusted, replica, |e| log_to_file(&e));
+                log_to_file(&format!("Listening for peers on {}", config().listen));
+            }
+            Err(e) => log_to_file(&format!("Cannot listen on {}: {}", config().listen, e)),
+        }
+    }
 
     loop {
         cycle_count += 1;
         log_to_file(&format!("Sync cycle {} starting...", cycle_count));
 
-        // 🔥 1. ALWAYS reload latest state from disk
-        state = MayaState::load(STATE_FILE, &state.node_id);
-
-        // 🔥 2. Sync if file changed
-        sync_with_peers(STATE_FILE, &mut last_hash);
-
-        // 🔥 3. Reload again in case merge modified file
-        state = MayaState::load(STATE_FILE, &state.node_id);
-
-        // 🔥 4. Process SSH log (only for new attackers)
-        if let Ok(log) = std::fs::read_to_string("/var/log/auth.log") {
-            for line in log.lines() {
-                if line.contains("Accepted password") || line.contains("Accepted publickey") {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    for part in &parts {
-                        if part.contains('.') && part.parse::<std::net::Ipv4Addr>().is_ok() {
-                            if !state.attackers.contains_key(*part) {
-                                state.observe_visit(part, "ssh");
-                                state.update_location(part, "ssh");
-                                log_to_file(&format!("New attacker detected via SSH: {}", part));
-                            }
-                            break;
-                        }
-                    }
-                }
+        // 🔥 1. Snapshot latest state and pending deltas under the lock
+        let snapshot = match lock_state() {
+            Ok(_lock) => load_state_or_recover(node_id).map(|state| (state, DeltaBuffer::load(&delta_file(), codec().key.as_ref()))),
+            Err(e) => {
+                log_to_file(&format!("Sync cycle {} skipped: {}", cycle_count, e));
+                None
             }
+        };
+        let Some((snapshot, pending)) = snapshot else {
+            thread::sleep(Duration::from_secs(config().sync_interval));
+            continue;
+        };
+
+        // 🔥 2. Ship buffered deltas (or full state) to peers that need them,
+        //       then reconcile digests with one peer to catch what pushes
+        //       missed. Mesh mode covers every peer, each in turn for
+        //       anti-entropy; gossip picks `fanout` of them and one partner
+        //       at random, so the cost per node stays flat as the mesh grows.
+        //       Peers in backoff after failing are left out
+        let peers = known_peers(&snapshot);
+        let now = now_millis();
+        let due: Vec<String> = peers.iter().filter(|peer| records.is_due(peer, now)).cloned().collect();
+        if due.len() < peers.len() {
+            log_to_file(&format!("Backing off {} failing peer(s)", peers.len() - due.len()));
         }
+        let mut rng = rand::thread_rng();
+        let (targets, partner) = match config().sync_mode {
+            SyncMode::Mesh => (due.clone(), due.get(cycle_count % due.len().max(1))),
+            SyncMode::Gossip => (
+                due.choose_multiple(&mut rng, config().fanout).cloned().collect(),
+                due.choose(&mut rng),
+            ),
+        };
+        let acks = sync_with_peers(&snapshot, &pending, &targets, &mut records);
+        // Skip the round if the push to the same peer just failed
+        let pulled = partner
+            .filter(|peer| records.is_due(peer, now_millis()))
+            .and_then(|peer| anti_entropy_round(&snapshot, peer, &mut records));
 
-        // 🔥 5. Save only if we actually changed state
-        state.save(STATE_FILE);
+        // 🔥 3-6. Under the lock, merge what was pulled, note acks, record
+        //        local detections and save. A failed write is retried next
+        //        cycle rather than stopping the daemon
+        let state = match lock_state().and_then(|_lock| update_state(node_id, pulled, acks, &peers, &mut records)) {
+            Ok(Some(state)) => state,
+            Ok(None) => {
+                thread::sleep(Duration::from_secs(config().sync_interval));
+                continue;
+            }
+            Err(e) => {
+                log_to_file(&format!("Sync cycle {} failed, retrying next cycle: {}", cycle_count, e));
+                thread::sleep(Duration::from_secs(config().sync_interval));
+                continue;
+            }
+        };
 
-        // 🔥 6. Update hash AFTER save
-        last_hash = hash_file(STATE_FILE);
+        // 🔥 7. Note when the replicated data changed (local ops, peer
+        //       merges or expiry); identical data hashes the same on every node
+        let hash = state.hash();
+        if hash != last_hash {
+            log_to_file(&format!("State changed: {} -> {}", last_hash, hash));
+            last_hash = hash;
+        }
 
         log_to_file(&format!(
             "Sync cycle {} complete. Current attackers: {}",
@@ -344,6 +1300,6 @@ fn run_daemon(mut state: MayaState) {
             state.attackers.len()
         ));
 
-        thread::sleep(Duration::from_secs(30));
+        thread::sleep(Duration::from_secs(config().sync_interval));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,105 @@
+//! What the daemon remembers about each peer across restarts: when it last
+//! synced, the last error, how many attempts in a row failed and the
+//! version vector the peer last acknowledged. Kept next to the state file
+//! (`<state>.peers`), sealed like the delta buffer when a key is set.
+//!
+//! A peer that keeps failing is retried after `sync_interval`, then twice
+//! that, and so on up to `MAX_BACKOFF`, instead of costing a connect
+//! timeout every cycle. One success resets it.
+
+use crate::crypto::StateKey;
+use crate::{load_sealed, save_sealed, VersionVector};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+/// Longest a failing peer waits between attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Sync history of one peer. Times are milliseconds since the epoch.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PeerRecord {
+    /// Node id the peer gave in its last handshake.
+    pub node_id: Option<String>,
+    pub last_success: Option<u64>,
+    pub last_failure: Option<u64>,
+    pub last_error: Option<String>,
+    /// Failed attempts since the last success.
+    pub failures: u32,
+    /// What the peer acknowledged having at its last successful sync.
+    pub version: VersionVector,
+    /// Not tried again before this.
+    pub retry_at: u64,
+}
+
+impl PeerRecord {
+    /// How long to wait after the current run of failures: `interval`
+    /// doubled for each failure after the first, capped at `MAX_BACKOFF`.
+    pub fn backoff(&self, interval: Duration) -> Duration {
+        if self.failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32 << (self.failures - 1).min(16);
+        interval.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+}
+
+/// Every peer's `PeerRecord`, by the peer entry it was synced under.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PeerRecords {
+    pub peers: BTreeMap<String, PeerRecord>,
+}
+
+impl PeerRecords {
+    /// An unreadable file loads empty: every peer is then due, as on a
+    /// first start.
+    pub fn load(path: &str, key: Option<&StateKey>) -> Self {
+        load_sealed(path, key)
+    }
+
+    pub fn save(&self, path: &str, key: Option<&StateKey>) -> io::Result<()> {
+        save_sealed(self, path, key)
+    }
+
+    pub fn get(&self, peer: &str) -> Option<&PeerRecord> {
+        self.peers.get(peer)
+    }
+
+    /// Whether `peer` is out of backoff at `now`. Unknown peers are due.
+    pub fn is_due(&self, peer: &str, now: u64) -> bool {
+        self.peers.get(peer).is_none_or(|record| now >= record.retry_at)
+    }
+
+    /// Note a successful sync with `node_id` (unknown over ssh), with the
+    /// version the peer acknowledged if the exchange carried one.
+    pub fn succeeded(&mut self, peer: &str, now: u64, node_id: Option<&str>, version: Option<&VersionVector>) {
+        let record = self.peers.entry(peer.to_string()).or_default();
+        if let Some(node_id) = node_id {
+            record.node_id = Some(node_id.to_string());
+        }
+        record.last_success = Some(now);
+        record.failures = 0;
+        record.retry_at = 0;
+        if let Some(version) = version {
+            record.version = version.clone();
+        }
+    }
+
+    /// Note a failed attempt and push the next one back. Returns how long
+    /// the peer is now left alone.
+    pub fn failed(&mut self, peer: &str, now: u64, error: &str, interval: Duration) -> Duration {
+        let record = self.peers.entry(peer.to_string()).or_default();
+        record.last_failure = Some(now);
+        record.last_error = Some(error.to_string());
+        record.failures = record.failures.saturating_add(1);
+        let backoff = record.backoff(interval);
+        record.retry_at = now + backoff.as_millis() as u64;
+        backoff
+    }
+
+    /// Forget peers that are no longer listed or members.
+    pub fn retain(&mut self, peers: &[String]) {
+        self.peers.retain(|peer, _| peers.contains(peer));
+    }
+}
@@ -0,0 +1,207 @@
+//! Ed25519 signatures on sync payloads, so a node only merges states and
+//! deltas from nodes it trusts. Each node signs with its own key:
+//!
+//! ```bash
+//! sudo syslogd-helper keygen-signing /etc/syslogd-helper/node.key
+//! ```
+//!
+//! and lists the public keys it accepts, one node per line, in its
+//! trusted keys file:
+//!
+//! ```text
+//! # node id     public key (hex)
+//! fake-web-01   3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b59da29
+//! ```
+//!
+//! A signed payload is `SIGNED_MAGIC`, the signer's node id (a big-endian
+//! u16 length, then the bytes), the 64-byte signature, then the payload as
+//! `MayaState::encode` wrote it. The signature covers the node id and the
+//! payload.
+
+use crate::crypto::{read_secret, write_secret};
+use crate::{Codec, LoadError, MayaState};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use rand::rngs::OsRng;
+use std::collections::BTreeMap;
+use std::{fmt, fs, io};
+
+/// Leading bytes of a signed payload.
+pub const SIGNED_MAGIC: &[u8] = b"MAYG\x01";
+
+pub fn is_signed(data: &[u8]) -> bool {
+    data.starts_with(SIGNED_MAGIC)
+}
+
+/// Why a payload was not accepted as coming from a trusted node.
+#[derive(Debug)]
+pub enum SignatureError {
+    Unsigned,
+    /// The envelope is cut short or garbled.
+    Malformed,
+    /// No trusted key is listed for the node that signed it.
+    UnknownSigner(String),
+    /// The signature does not match any key trusted for the signer: forged,
+    /// or modified in transit.
+    BadSignature(String),
+    /// Signed by one node but claims to come from another.
+    WrongOrigin { signer: String, origin: String },
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "payload is not signed"),
+            SignatureError::Malformed => write!(f, "signed payload is malformed"),
+            SignatureError::UnknownSigner(node) => write!(f, "no trusted key for signer {}", node),
+            SignatureError::BadSignature(node) => write!(f, "bad signature from {}", node),
+            SignatureError::WrongOrigin { signer, origin } => {
+                write!(f, "signed by {} but claims to come from {}", signer, origin)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Split a signed payload into signer, signature and body.
+fn split(data: &[u8]) -> Option<(&str, Signature, &[u8])> {
+    let rest = data.strip_prefix(SIGNED_MAGIC)?;
+    let (len, rest) = rest.split_first_chunk::<2>()?;
+    let len = u16::from_be_bytes(*len) as usize;
+    if rest.len() < len + SIGNATURE_LENGTH {
+        return None;
+    }
+    let (signer, rest) = rest.split_at(len);
+    let (signature, body) = rest.split_at(SIGNATURE_LENGTH);
+    let signature = Signature::from_slice(signature).ok()?;
+    Some((std::str::from_utf8(signer).ok()?, signature, body))
+}
+
+// What the signature covers: the signer's node id, then the body
+fn signed_bytes(signer: &str, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(2 + signer.len() + body.len());
+    message.extend_from_slice(&(signer.len() as u16).to_be_bytes());
+    message.extend_from_slice(signer.as_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// The payload inside a signed envelope, without checking the signature;
+/// unsigned data is returned as is. For nodes that do not verify.
+pub fn unsigned(data: &[u8]) -> &[u8] {
+    match split(data) {
+        Some((_, _, body)) => body,
+        None => data,
+    }
+}
+
+/// This node's signing key.
+pub struct NodeKey(SigningKey);
+
+impl fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeKey({})", self.public_hex())
+    }
+}
+
+impl NodeKey {
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+
+    /// Read a key file, refusing one not owned by root or that group or
+    /// others can access.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self(SigningKey::from_bytes(&read_secret(path)?)))
+    }
+
+    /// Write the key to a new file readable only by its owner.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        write_secret(path, self.0.as_bytes())
+    }
+
+    /// The public key, as it goes in other nodes' trusted keys files.
+    pub fn public_hex(&self) -> String {
+        hex::encode(self.0.verifying_key().as_bytes())
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.0.verifying_key()
+    }
+
+    /// Wrap `payload` in an envelope signed as `node_id`.
+    pub fn sign(&self, node_id: &str, payload: &[u8]) -> Vec<u8> {
+        let signature = self.0.sign(&signed_bytes(node_id, payload));
+        let mut out = SIGNED_MAGIC.to_vec();
+        out.extend_from_slice(&(node_id.len() as u16).to_be_bytes());
+        out.extend_from_slice(node_id.as_bytes());
+        out.extend_from_slice(&signature.to_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+}
+
+/// Public keys this node accepts payloads from, by node id. A node may
+/// have several, e.g. while its key is being replaced.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys {
+    keys: BTreeMap<String, Vec<VerifyingKey>>,
+}
+
+impl TrustedKeys {
+    /// Read a trusted keys file: `<node id> <hex public key>` per line,
+    /// blank lines and `#` comments ignored.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut trusted = Self::default();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |msg: &str| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{} line {}: {}", path, n + 1, msg))
+            };
+            let (node, key) = line.split_once(char::is_whitespace).ok_or_else(|| invalid("expected <node id> <key>"))?;
+            let key: [u8; 32] = hex::decode(key.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| invalid("key must be 64 hex characters"))?;
+            let key = VerifyingKey::from_bytes(&key).map_err(|_| invalid("not an Ed25519 public key"))?;
+            trusted.add(node, key);
+        }
+        Ok(trusted)
+    }
+
+    pub fn add(&mut self, node_id: &str, key: VerifyingKey) {
+        self.keys.entry(node_id.to_string()).or_default().push(key);
+    }
+
+    /// Check a signed payload. Returns the signer and the payload.
+    pub fn verify<'a>(&self, data: &'a [u8]) -> Result<(&'a str, &'a [u8]), SignatureError> {
+        if !is_signed(data) {
+            return Err(SignatureError::Unsigned);
+        }
+        let (signer, signature, body) = split(data).ok_or(SignatureError::Malformed)?;
+        let keys = self.keys.get(signer).ok_or_else(|| SignatureError::UnknownSigner(signer.to_string()))?;
+        let message = signed_bytes(signer, body);
+        if keys.iter().any(|key| key.verify(&message, &signature).is_ok()) {
+            Ok((signer, body))
+        } else {
+            Err(SignatureError::BadSignature(signer.to_string()))
+        }
+    }
+
+    /// Verify and decode a sync payload. It must be signed by a trusted
+    /// key of the node it says it comes from.
+    pub fn decode(&self, data: &[u8], codec: &Codec) -> Result<MayaState, LoadError> {
+        let (signer, body) = self.verify(data).map_err(LoadError::Signature)?;
+        let state = MayaState::decode(body, codec)?;
+        if state.node_id != signer {
+            return Err(LoadError::Signature(SignatureError::WrongOrigin {
+                signer: signer.to_string(),
+                origin: state.node_id,
+            }));
+        }
+        Ok(state)
+    }
+}
@@ -0,0 +1,368 @@
+//! Where a `MayaState` lives between invocations.
+//!
+//! - `FileStorage`: the state file, its op log and backups (the default).
+//! - `MemoryStorage`: nothing touches disk, for tests.
+//! - `SqliteStorage` (feature `sqlite`): one row per attacker, so
+//!   looking up or updating an attacker leaves the others alone.
+
+use crate::{AttackerState, Codec, Crdt, LoadError, MayaState};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+/// Which `Storage` the binary uses, set by `storage` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    File,
+    Sqlite,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Backend::File),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(format!("unknown storage {:?} (expected file or sqlite)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::File => write!(f, "file"),
+            Backend::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+pub trait Storage: Send + Sync {
+    /// The stored state, or a fresh one for `node_id` if nothing is
+    /// stored yet.
+    fn load(&self, node_id: &str) -> Result<MayaState, LoadError>;
+
+    /// Record `delta`, produced by `op`, which has already been joined into
+    /// `state`.
+    fn commit(&self, state: &MayaState, op: &str, delta: &MayaState) -> io::Result<()>;
+
+    /// Store `state` as a whole, e.g. after compaction, which is not a delta.
+    fn snapshot(&self, state: &MayaState) -> io::Result<()>;
+
+    /// After `state` was compacted, strip what compaction collected from
+    /// anything kept besides the state that could be replayed into it.
+    fn strip_compacted(&self, _state: &MayaState) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Seal whatever is still stored in plaintext now that a key is set,
+    /// since reading with a key refuses plaintext. Returns what was sealed.
+    fn seal_plaintext(&self) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Whether commits are waiting to be folded into a snapshot.
+    fn has_unsnapshotted_ops(&self) -> bool {
+        false
+    }
+
+    /// Restore from a backup after `load` failed. Returns the state and
+    /// where it came from.
+    fn recover(&self) -> Result<(MayaState, String), LoadError> {
+        Err(LoadError::NoBackup)
+    }
+
+    /// The stored state for a command that touches only the attackers in
+    /// `ips` (none for credentials or sessions). Backends that keep
+    /// attackers apart leave the others out; the rest load everything.
+    /// Such a state may be committed to but must never be snapshotted.
+    fn load_attackers(&self, node_id: &str, _ips: &[&str]) -> Result<MayaState, LoadError> {
+        self.load(node_id)
+    }
+
+    /// One attacker, without keeping the rest in memory where the backend
+    /// allows it.
+    fn attacker(&self, ip: &str) -> Result<Option<AttackerState>, LoadError> {
+        Ok(self.load_attackers("", &[ip])?.attackers.remove(ip))
+    }
+}
+
+/// The state file at `path` with its op log, backups and quarantine; see
+/// `MayaState::load_with` and `MayaState::commit`.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    pub path: String,
+    pub codec: Codec,
+}
+
+impl FileStorage {
+    pub fn new(path: &str, codec: Codec) -> Self {
+        Self { path: path.to_string(), codec }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self, node_id: &str) -> Result<MayaState, LoadError> {
+        MayaState::load_with(&self.path, node_id, &self.codec)
+    }
+
+    fn commit(&self, state: &MayaState, op: &str, delta: &MayaState) -> io::Result<()> {
+        state.commit(&self.path, op, delta, &self.codec)
+    }
+
+    fn snapshot(&self, state: &MayaState) -> io::Result<()> {
+        state.snapshot(&self.path, &self.codec)
+    }
+
+    fn strip_compacted(&self, state: &MayaState) -> io::Result<()> {
+        state.strip_oplogs(&self.path, &self.codec)
+    }
+
+    fn has_unsnapshotted_ops(&self) -> bool {
+        MayaState::has_unsnapshotted_ops(&self.path)
+    }
+
+    fn recover(&self) -> Result<(MayaState, String), LoadError> {
+        MayaState::recover(&self.path, &self.codec)
+    }
+
+    fn seal_plaintext(&self) -> io::Result<Vec<String>> {
+        match &self.codec.key {
+            Some(key) => crate::seal_plaintext(&self.path, key),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Keeps the state in memory. Commits are merged in, the way replaying the
+/// op log would.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<Option<MayaState>>,
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self, node_id: &str) -> Result<MayaState, LoadError> {
+        let stored = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(stored.clone().unwrap_or_else(|| MayaState::new(node_id)))
+    }
+
+    fn commit(&self, state: &MayaState, _op: &str, delta: &MayaState) -> io::Result<()> {
+        let mut stored = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        stored.get_or_insert_with(|| MayaState::new(&state.node_id)).merge(delta);
+        Ok(())
+    }
+
+    fn snapshot(&self, state: &MayaState) -> io::Result<()> {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = Some(state.clone());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::Storage;
+    use crate::crypto::{self, StateKey};
+    use crate::{AttackerState, Codec, Encoding, LoadError, MayaState, ParseError, SCHEMA_VERSION};
+    use rusqlite::{params, Connection, OptionalExtension};
+    use serde_json::Value;
+    use std::io;
+    use std::sync::{Mutex, MutexGuard};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS meta (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            schema_version INTEGER NOT NULL,
+            data BLOB NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS attackers (
+            ip TEXT PRIMARY KEY,
+            data BLOB NOT NULL
+        );
+    ";
+
+    fn db(e: rusqlite::Error) -> io::Error {
+        io::Error::other(e)
+    }
+
+    /// A SQLite database holding one `attackers` row per IP and a single
+    /// `meta` row with everything else (clocks, version vector,
+    /// credentials, sessions). Row data is JSON, sealed when a key is set;
+    /// the IP column stays readable so rows can be looked up.
+    #[derive(Debug)]
+    pub struct SqliteStorage {
+        conn: Mutex<Connection>,
+        codec: Codec,
+    }
+
+    impl SqliteStorage {
+        pub fn open(path: &str, key: Option<StateKey>) -> io::Result<Self> {
+            Self::with_connection(Connection::open(path).map_err(db)?, key)
+        }
+
+        pub fn open_in_memory(key: Option<StateKey>) -> io::Result<Self> {
+            Self::with_connection(Connection::open_in_memory().map_err(db)?, key)
+        }
+
+        fn with_connection(conn: Connection, key: Option<StateKey>) -> io::Result<Self> {
+            conn.execute_batch(SCHEMA).map_err(db)?;
+            Ok(Self { conn: Mutex::new(conn), codec: Codec { encoding: Encoding::Json, key } })
+        }
+
+        fn conn(&self) -> MutexGuard<'_, Connection> {
+            self.conn.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        fn row_json(&self, data: Vec<u8>) -> Result<Value, LoadError> {
+            let plain = self.codec.open(data)?;
+            serde_json::from_slice(&plain).map_err(|e| LoadError::Io(e.into()))
+        }
+
+        // The meta row plus the attacker rows of `ips` (all of them for
+        // None), put back together as a state file's JSON and parsed like
+        // one so that every row goes through the schema migrations
+        fn assemble(&self, conn: &Connection, node_id: &str, ips: Option<&[&str]>) -> Result<MayaState, LoadError> {
+            let meta: Option<(i64, Vec<u8>)> = conn
+                .query_row("SELECT schema_version, data FROM meta", [], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()
+                .map_err(db)?;
+            let Some((version, data)) = meta else { return Ok(MayaState::new(node_id)) };
+            let version = version as u64;
+            if version > SCHEMA_VERSION {
+                return Err(LoadError::Newer { found: version });
+            }
+
+            let mut state = self.row_json(data)?;
+            let mut attackers = serde_json::Map::new();
+            let rows: Vec<(String, Vec<u8>)> = match ips {
+                None => conn.prepare("SELECT ip, data FROM attackers")
+                    .and_then(|mut stmt| stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect()),
+                Some(ips) => ips.iter()
+                    .filter_map(|ip| {
+                        conn.query_row("SELECT ip, data FROM attackers WHERE ip = ?1", [ip], |row| Ok((row.get(0)?, row.get(1)?)))
+                            .optional()
+                            .transpose()
+                    })
+                    .collect(),
+            }.map_err(db)?;
+            for (ip, data) in rows {
+                attackers.insert(ip, self.row_json(data)?);
+            }
+            if let Some(obj) = state.as_object_mut() {
+                obj.insert("attackers".into(), attackers.into());
+                obj.insert("schema_version".into(), version.into());
+            }
+
+            let data = serde_json::to_vec(&state).map_err(|e| LoadError::Io(e.into()))?;
+            MayaState::parse(&data).map_err(ParseError::into_load_error)
+        }
+
+        fn schema_version(conn: &Connection) -> io::Result<Option<u64>> {
+            conn.query_row("SELECT schema_version FROM meta", [], |row| row.get::<_, i64>(0))
+                .optional()
+                .map(|version| version.map(|v| v as u64))
+                .map_err(db)
+        }
+
+        fn put_meta(&self, conn: &Connection, state: &MayaState) -> io::Result<()> {
+            let mut value = serde_json::to_value(state)?;
+            if let Some(obj) = value.as_object_mut() {
+                obj.remove("attackers");
+            }
+            let data = self.codec.seal(serde_json::to_vec(&value)?);
+            conn.execute(
+                "INSERT INTO meta (id, schema_version, data) VALUES (0, ?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET schema_version = ?1, data = ?2",
+                params![SCHEMA_VERSION as i64, data],
+            ).map_err(db)?;
+            Ok(())
+        }
+
+        fn put_attacker(&self, conn: &Connection, ip: &str, attacker: &AttackerState) -> io::Result<()> {
+            let data = self.codec.seal(serde_json::to_vec(attacker)?);
+            conn.execute(
+                "INSERT INTO attackers (ip, data) VALUES (?1, ?2)
+                 ON CONFLICT (ip) DO UPDATE SET data = ?2",
+                params![ip, data],
+            ).map_err(db)?;
+            Ok(())
+        }
+    }
+
+    impl Storage for SqliteStorage {
+        /// Reassembles the state and loads it like a state file, so the
+        /// schema migrations apply to databases too.
+        fn load(&self, node_id: &str) -> Result<MayaState, LoadError> {
+            self.assemble(&self.conn(), node_id, None)
+        }
+
+        /// Only decodes the rows of `ips`, through the same migrations.
+        fn load_attackers(&self, node_id: &str, ips: &[&str]) -> Result<MayaState, LoadError> {
+            self.assemble(&self.conn(), node_id, Some(ips))
+        }
+
+        /// Rewrites the meta row and only the attackers `delta` touched,
+        /// unless the rows are from an older schema: rows carry no version
+        /// of their own, so every one is migrated and rewritten before the
+        /// meta row says they are current.
+        fn commit(&self, state: &MayaState, _op: &str, delta: &MayaState) -> io::Result<()> {
+            let mut conn = self.conn();
+            let tx = conn.transaction().map_err(db)?;
+            if Self::schema_version(&tx)?.is_some_and(|version| version < SCHEMA_VERSION) {
+                let upgraded = self.assemble(&tx, &state.node_id, None).map_err(io::Error::other)?;
+                for (ip, attacker) in upgraded.attackers.iter().filter(|(ip, _)| !delta.attackers.contains_key(*ip)) {
+                    self.put_attacker(&tx, ip, attacker)?;
+                }
+            }
+            self.put_meta(&tx, state)?;
+            for ip in delta.attackers.keys() {
+                if let Some(attacker) = state.attackers.get(ip) {
+                    self.put_attacker(&tx, ip, attacker)?;
+                }
+            }
+            tx.commit().map_err(db)
+        }
+
+        fn snapshot(&self, state: &MayaState) -> io::Result<()> {
+            let mut conn = self.conn();
+            let tx = conn.transaction().map_err(db)?;
+            tx.execute("DELETE FROM attackers", []).map_err(db)?;
+            self.put_meta(&tx, state)?;
+            for (ip, attacker) in &state.attackers {
+                self.put_attacker(&tx, ip, attacker)?;
+            }
+            tx.commit().map_err(db)
+        }
+
+        /// Seals the meta row and every attacker row still in plaintext.
+        fn seal_plaintext(&self) -> io::Result<Vec<String>> {
+            let Some(key) = &self.codec.key else { return Ok(Vec::new()) };
+            let mut conn = self.conn();
+            let tx = conn.transaction().map_err(db)?;
+            let mut sealed = Vec::new();
+            for (table, column) in [("meta", "id"), ("attackers", "ip")] {
+                let rows: Vec<(rusqlite::types::Value, Vec<u8>)> = tx
+                    .prepare(&format!("SELECT {}, data FROM {}", column, table))
+                    .and_then(|mut stmt| stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect())
+                    .map_err(db)?;
+                for (id, data) in rows.into_iter().filter(|(_, data)| !crypto::is_sealed(data)) {
+                    tx.execute(&format!("UPDATE {} SET data = ?1 WHERE {} = ?2", table, column), params![key.seal(&data), id])
+                        .map_err(db)?;
+                    sealed.push(match &id {
+                        rusqlite::types::Value::Text(ip) => format!("attacker {}", ip),
+                        _ => "meta".to_string(),
+                    });
+                }
+            }
+            tx.commit().map_err(db)?;
+            Ok(sealed)
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! Native sync between daemons over TCP.
+//!
+//! Every frame is a 4-byte big-endian length, a kind byte, then the body:
+//!
+//! | kind | frame    | body                                   |
+//! |------|----------|----------------------------------------|
+//! | 1    | `Hello`  | JSON `{"node_id": .., "protocol": ..}` |
+//! | 2    | `Push`   | a state or delta, as `MayaState::encode` writes it |
+//! | 3    | `Ack`    | JSON `{"version": ..}`                 |
+//! | 4    | `Error`  | UTF-8 message                          |
+//! | 5    | `Pull`   | JSON `StateDigest` of the client       |
+//! | 6    | `Digest` | JSON `StateDigest` of the server       |
+//! | 7    | `Auth`   | the server's challenge, signed by the client |
+//!
+//! The client sends `Hello` and the server answers with its own `Hello`
+//! carrying a random challenge (or `Error` if it speaks none of the same
+//! protocol versions). The client signs the challenge and the server's
+//! node id with its `NodeKey` and sends that as `Auth`; the server serves
+//! nothing until the signature checks out against its `TrustedKeys` for
+//! the node id the client gave. Then:
+//!
+//! - push: the client sends `Push`, the server merges it and answers
+//!   `Ack` with its version vector, or `Error`.
+//! - pull (protocol 2): the client sends `Pull` with its digest, the
+//!   server answers `Digest` with its own and `Push` with the parts of its
+//!   state that differ.
+//! - push-pull (protocol 2): a pull, then a push of the parts of the
+//!   client's state that differ from the server's digest.
+//!
+//! Then both sides close.
+//!
+//! Until the client is authenticated the server reads frames of at most
+//! `MAX_HANDSHAKE_FRAME` bytes. It serves `MAX_CONNECTIONS` clients at a
+//! time and drops one whose exchange runs past `DEADLINE`.
+
+use crate::signing::{NodeKey, TrustedKeys};
+use crate::{StateDigest, VersionVector};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Protocol 2 added pull, protocol 3 the challenge clients sign.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest protocol version still spoken. Older clients cannot
+/// authenticate, so every node moves to protocol 3 together.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// Port peers listen on unless `peers.conf` gives one.
+pub const DEFAULT_PORT: u16 = 6514;
+
+/// Largest frame either side accepts. Bodies are read as they arrive, so
+/// a length alone never makes us allocate this much.
+pub const MAX_FRAME: usize = 16 << 20;
+
+/// Largest `Hello` or `Auth` frame, all a client may send before it has
+/// authenticated.
+pub const MAX_HANDSHAKE_FRAME: usize = 4 << 10;
+
+/// Connections the listener serves at once; more are refused.
+pub const MAX_CONNECTIONS: usize = 16;
+
+/// Connect, read and write timeout for each step of an exchange.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a whole exchange may take, however steadily the other side
+/// trickles in bytes.
+pub const DEADLINE: Duration = Duration::from_secs(60);
+
+/// What the daemon does each cycle besides pushing its own deltas, set by
+/// `anti_entropy` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AntiEntropy {
+    /// Push only.
+    Off,
+    /// Also pull what the next peer in turn has that we lack.
+    Pull,
+    /// Also pull from the next peer in turn and push back what it lacks.
+    #[default]
+    PushPull,
+}
+
+impl std::str::FromStr for AntiEntropy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AntiEntropy::Off),
+            "pull" => Ok(AntiEntropy::Pull),
+            "push-pull" => Ok(AntiEntropy::PushPull),
+            _ => Err(format!("unknown anti_entropy {:?} (expected off, pull or push-pull)", s)),
+        }
+    }
+}
+
+/// Which peers the daemon pushes to each cycle, set by `sync_mode` in the
+/// config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    /// Every line of `peers.conf`, and anti-entropy with each in turn.
+    #[default]
+    Mesh,
+    /// `fanout` peers picked at random from the members `MayaState` knows
+    /// of, with `peers.conf` as seeds; anti-entropy with one at random.
+    Gossip,
+}
+
+impl std::str::FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mesh" => Ok(SyncMode::Mesh),
+            "gossip" => Ok(SyncMode::Gossip),
+            _ => Err(format!("unknown sync_mode {:?} (expected mesh or gossip)", s)),
+        }
+    }
+}
+
+const HELLO: u8 = 1;
+const PUSH: u8 = 2;
+const ACK: u8 = 3;
+const ERROR: u8 = 4;
+const PULL: u8 = 5;
+const DIGEST: u8 = 6;
+const AUTH: u8 = 7;
+
+// Prefix of what a client signs to authenticate, so that no other signed
+// data can pass for it
+const AUTH_CONTEXT: &[u8] = b"syslogd-helper sync auth\0";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub node_id: String,
+    pub protocol: u32,
+    /// What the client must sign, in hex; only the server sends one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ack {
+    pub version: VersionVector,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Hello(Hello),
+    Push(Vec<u8>),
+    Ack(Ack),
+    Error(String),
+    Pull(StateDigest),
+    Digest(StateDigest),
+    Auth(Vec<u8>),
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Frame {
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let (kind, body) = match self {
+            Frame::Hello(hello) => (HELLO, serde_json::to_vec(hello)?),
+            Frame::Push(payload) => (PUSH, payload.clone()),
+            Frame::Ack(ack) => (ACK, serde_json::to_vec(ack)?),
+            Frame::Error(msg) => (ERROR, msg.as_bytes().to_vec()),
+            Frame::Pull(digest) => (PULL, serde_json::to_vec(digest)?),
+            Frame::Digest(digest) => (DIGEST, serde_json::to_vec(digest)?),
+            Frame::Auth(signed) => (AUTH, signed.clone()),
+        };
+        if body.len() + 1 > MAX_FRAME {
+            return Err(invalid(format!("frame of {} bytes exceeds {}", body.len() + 1, MAX_FRAME)));
+        }
+        let mut frame = Vec::with_capacity(5 + body.len());
+        frame.extend_from_slice(&(body.len() as u32 + 1).to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(&body);
+        w.write_all(&frame)?;
+        w.flush()
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        Self::read_limited(r, MAX_FRAME)
+    }
+
+    /// Read a frame of at most `limit` bytes.
+    pub fn read_limited(r: &mut impl Read, limit: usize) -> io::Result<Self> {
+        let mut len = [0u8; 4];
+        r.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > limit {
+            return Err(invalid(format!("bad frame length {}", len)));
+        }
+        let mut frame = Vec::new();
+        r.take(len as u64).read_to_end(&mut frame)?;
+        if frame.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame cut short"));
+        }
+        let body = frame.split_off(1);
+        match frame[0] {
+            HELLO => Ok(Frame::Hello(serde_json::from_slice(&body)?)),
+            PUSH => Ok(Frame::Push(body)),
+            ACK => Ok(Frame::Ack(serde_json::from_slice(&body)?)),
+            ERROR => Ok(Frame::Error(String::from_utf8_lossy(&body).into_owned())),
+            PULL => Ok(Frame::Pull(serde_json::from_slice(&body)?)),
+            DIGEST => Ok(Frame::Digest(serde_json::from_slice(&body)?)),
+            AUTH => Ok(Frame::Auth(body)),
+            kind => Err(invalid(format!("unknown frame kind {}", kind))),
+        }
+    }
+}
+
+// A connection whose reads give up once `deadline` has passed
+struct Conn {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Conn {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Self { stream, deadline: Instant::now() + DEADLINE })
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "exchange ran past its deadline"));
+        }
+        self.stream.set_read_timeout(Some(left.min(TIMEOUT)))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Read the next frame, turning the peer's `Error` into ours
+fn expect(conn: &mut Conn, limit: usize) -> io::Result<Frame> {
+    match Frame::read_limited(conn, limit)? {
+        Frame::Error(msg) => Err(io::Error::other(format!("peer refused: {}", msg))),
+        frame => Ok(frame),
+    }
+}
+
+// What a client signs to prove who it is to `server`
+fn auth_message(challenge: &[u8], server: &str) -> Vec<u8> {
+    [AUTH_CONTEXT, challenge, server.as_bytes()].concat()
+}
+
+/// `host:port` for a `peers.conf` entry, adding `DEFAULT_PORT` if the
+/// entry has none.
+pub fn peer_addr(peer: &str) -> String {
+    match peer.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => peer.to_string(),
+        _ => format!("{}:{}", peer, DEFAULT_PORT),
+    }
+}
+
+/// The addresses a `peers.conf` entry or member address resolves to;
+/// none if it does not.
+pub fn resolve(peer: &str) -> Vec<SocketAddr> {
+    peer_addr(peer).to_socket_addrs().map(Iterator::collect).unwrap_or_default()
+}
+
+/// The host part of a `peers.conf` entry.
+pub fn peer_host(peer: &str) -> &str {
+    match peer.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => peer,
+    }
+}
+
+fn connect(addr: &str) -> io::Result<Conn> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, format!("{} does not resolve", addr));
+    for candidate in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&candidate, TIMEOUT) {
+            Ok(stream) => return Conn::new(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+// Connect and exchange hellos
+fn handshake(addr: &str, node_id: &str) -> io::Result<(Conn, Hello)> {
+    let mut conn = connect(addr)?;
+    Frame::Hello(Hello { node_id: node_id.to_string(), protocol: PROTOCOL_VERSION, challenge: None }).write_to(&mut conn)?;
+    match expect(&mut conn, MAX_HANDSHAKE_FRAME)? {
+        Frame::Hello(hello) => Ok((conn, hello)),
+        _ => Err(invalid("expected hello".into())),
+    }
+}
+
+// Connect, exchange hellos and sign the server's challenge as `node_id`
+fn sign_in(addr: &str, node_id: &str, key: &NodeKey) -> io::Result<(Conn, Hello)> {
+    let (mut conn, hello) = handshake(addr, node_id)?;
+    let challenge = hello.challenge.as_deref()
+        .and_then(|challenge| hex::decode(challenge).ok())
+        .ok_or_else(|| invalid(format!("{} sent no challenge to sign", hello.node_id)))?;
+    Frame::Auth(key.sign(node_id, &auth_message(&challenge, &hello.node_id))).write_to(&mut conn)?;
+    Ok((conn, hello))
+}
+
+/// Check that a daemon answers at `addr`. Returns its hello.
+pub fn probe(addr: &str, node_id: &str) -> io::Result<Hello> {
+    handshake(addr, node_id).map(|(_, hello)| hello)
+}
+
+/// Push `payload` to the daemon at `addr`, signing in with `key`, and
+/// wait for its ack. Returns the peer's hello and ack.
+pub fn push(addr: &str, node_id: &str, key: &NodeKey, payload: Vec<u8>) -> io::Result<(Hello, Ack)> {
+    let (mut conn, hello) = sign_in(addr, node_id, key)?;
+    let ack = send_push(&mut conn, payload)?;
+    Ok((hello, ack))
+}
+
+fn send_push(conn: &mut Conn, payload: Vec<u8>) -> io::Result<Ack> {
+    Frame::Push(payload).write_to(conn)?;
+    match expect(conn, MAX_FRAME)? {
+        Frame::Ack(ack) => Ok(ack),
+        _ => Err(invalid("expected ack".into())),
+    }
+}
+
+/// What a pull brought back from the peer.
+#[derive(Debug, Clone)]
+pub struct Pulled {
+    pub hello: Hello,
+    /// The peer's digest, to work out what it lacks.
+    pub digest: StateDigest,
+    /// The parts of the peer's state that differ from ours, encoded.
+    pub payload: Vec<u8>,
+}
+
+/// Run a pull, or a push-pull, with the daemon at `addr`, signing in
+/// with `key`. `digest` is ours; `respond` sees what came back and
+/// returns what to push in turn, or `None` to only pull. Returns what was
+/// pulled and the ack for the push, if there was one.
+pub fn push_pull(
+    addr: &str,
+    node_id: &str,
+    key: &NodeKey,
+    digest: &StateDigest,
+    respond: impl FnOnce(&Pulled) -> io::Result<Option<Vec<u8>>>,
+) -> io::Result<(Pulled, Option<Ack>)> {
+    let (mut conn, hello) = sign_in(addr, node_id, key)?;
+    Frame::Pull(digest.clone()).write_to(&mut conn)?;
+    let Frame::Digest(remote) = expect(&mut conn, MAX_FRAME)? else {
+        return Err(invalid("expected digest".into()));
+    };
+    let Frame::Push(payload) = expect(&mut conn, MAX_FRAME)? else {
+        return Err(invalid("expected push".into()));
+    };
+    let pulled = Pulled { hello, digest: remote, payload };
+
+    let ack = match respond(&pulled)? {
+        Some(payload) => Some(send_push(&mut conn, payload)?),
+        None => None,
+    };
+    Ok((pulled, ack))
+}
+
+/// The server's side of a sync: this node's state as peers see it.
+pub trait Replica: Send + Sync {
+    /// Merge a pushed payload and return the version vector after, or a
+    /// reason to send back.
+    fn merge(&self, from: &Hello, payload: &[u8]) -> Result<VersionVector, String>;
+
+    /// Our digest, and the encoded parts of our state that differ from
+    /// `remote`.
+    fn pull(&self, from: &Hello, remote: &StateDigest) -> Result<(StateDigest, Vec<u8>), String>;
+}
+
+// Check the client's `Auth` against the challenge we sent it
+fn authenticate(trusted: &TrustedKeys, hello: &Hello, challenge: &[u8], node_id: &str, signed: &[u8]) -> Result<(), String> {
+    let (signer, body) = trusted.verify(signed).map_err(|e| e.to_string())?;
+    if signer != hello.node_id {
+        return Err(format!("signed by {} but says it is {}", signer, hello.node_id));
+    }
+    if body != auth_message(challenge, node_id) {
+        return Err(format!("{} signed the wrong challenge", signer));
+    }
+    Ok(())
+}
+
+// Send `msg` as an `Error` and fail with it
+fn refuse(conn: &mut Conn, msg: String) -> io::Result<()> {
+    Frame::Error(msg.clone()).write_to(conn)?;
+    Err(invalid(msg))
+}
+
+/// Serve one connection, to a client that signs in with a key in
+/// `trusted`.
+pub fn serve(stream: TcpStream, node_id: &str, trusted: &TrustedKeys, replica: &dyn Replica) -> io::Result<()> {
+    let mut conn = Conn::new(stream)?;
+
+    let Frame::Hello(hello) = Frame::read_limited(&mut conn, MAX_HANDSHAKE_FRAME)? else {
+        return refuse(&mut conn, "expected hello".into());
+    };
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol) {
+        let msg = format!(
+            "protocol {} not supported, this node speaks {} to {}",
+            hello.protocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        return refuse(&mut conn, msg);
+    }
+    let challenge: [u8; 32] = rand::random();
+    let ours = Hello { node_id: node_id.to_string(), protocol: PROTOCOL_VERSION, challenge: Some(hex::encode(challenge)) };
+    Frame::Hello(ours).write_to(&mut conn)?;
+
+    // A peer that hangs up here was only probing
+    let frame = match Frame::read_limited(&mut conn, MAX_HANDSHAKE_FRAME) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        frame => frame?,
+    };
+    let Frame::Auth(signed) = frame else {
+        return refuse(&mut conn, "expected auth".into());
+    };
+    if let Err(msg) = authenticate(trusted, &hello, &challenge, node_id, &signed) {
+        return refuse(&mut conn, format!("not authenticated: {}", msg));
+    }
+
+    let mut pulled = false;
+    loop {
+        // A peer that hangs up between requests is done: after signing in
+        // it was probing, after a pull it only wanted to pull
+        let frame = match Frame::read_from(&mut conn) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            frame => frame?,
+        };
+        match frame {
+            Frame::Pull(remote) if !pulled => {
+                pulled = true;
+                match replica.pull(&hello, &remote) {
+                    Ok((digest, payload)) => {
+                        Frame::Digest(digest).write_to(&mut conn)?;
+                        Frame::Push(payload).write_to(&mut conn)?;
+                    }
+                    Err(msg) => return Frame::Error(msg).write_to(&mut conn),
+                }
+            }
+            Frame::Push(payload) => {
+                return match replica.merge(&hello, &payload) {
+                    Ok(version) => Frame::Ack(Ack { version }).write_to(&mut conn),
+                    Err(msg) => Frame::Error(msg).write_to(&mut conn),
+                };
+            }
+            _ => return refuse(&mut conn, "expected push or pull".into()),
+        }
+    }
+}
+
+// One of the listener's `MAX_CONNECTIONS`, given back when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < MAX_CONNECTIONS).then_some(n + 1))
+            .ok()
+            .map(|_| Slot(active.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accept connections on `listener` in the background, serving each on
+/// its own thread, up to `MAX_CONNECTIONS` at once, to clients that sign
+/// in with a key in `trusted`. `on_error` hears about connections that
+/// failed.
+pub fn spawn_listener(
+    listener: TcpListener,
+    node_id: String,
+    trusted: Arc<TrustedKeys>,
+    replica: Arc<dyn Replica>,
+    on_error: impl Fn(String) + Send + Sync + 'static,
+) -> thread::JoinHandle<()> {
+    let on_error = Arc::new(on_error);
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    on_error(format!("accept failed: {}", e));
+                    continue;
+                }
+            };
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            let Some(slot) = Slot::take(&active) else {
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let _ = Frame::Error("busy, try again later".into()).write_to(&mut stream);
+                on_error(format!("refused sync from {}: {} already running", peer, MAX_CONNECTIONS));
+                continue;
+            };
+            let (node_id, trusted, replica, on_error) = (node_id.clone(), trusted.clone(), replica.clone(), on_error.clone());
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(e) = serve(stream, &node_id, &trusted, replica.as_ref()) {
+                    on_error(format!("sync from {} failed: {}", peer, e));
+                }
+            });
+        }
+    })
+}
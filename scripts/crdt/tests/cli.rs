@@ -0,0 +1,668 @@
+//! The binary run the way decoys run it, with every path pointed into a
+//! temp dir through the config file, environment and flags.
+
+use maya_crdt::peers::PeerRecords;
+use maya_crdt::{Codec, MayaState, VersionVector};
+use std::fs;
+use std::process::{Command, Output};
+use std::thread;
+
+mod common;
+use common::TempDir;
+
+/// A node on this host whose files all live in `dir`.
+struct Node<'a> {
+    dir: &'a TempDir,
+    id: &'a str,
+}
+
+impl Node<'_> {
+    fn state_file(&self) -> String {
+        self.dir.file(&format!("{}.state", self.id))
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_maya-crdt"));
+        cmd.env("SYSLOGD_HELPER_CONFIG", self.dir.file("absent.toml"))
+            .env("SYSLOGD_HELPER_LOG_FILE", self.dir.file(&format!("{}.log", self.id)))
+            .args(["--state-file", &self.state_file(), "--node-id", self.id]);
+        cmd
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        let output = self.command().args(args).output().unwrap();
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    fn load(&self) -> MayaState {
+        MayaState::load(&self.state_file(), self.id).unwrap()
+    }
+
+    fn signing_key(&self) -> String {
+        self.dir.file(&format!("{}.key", self.id))
+    }
+
+    /// Flags to sign in to peers and serve those in `trusted`.
+    fn sync_keys(&self, trusted: &str) -> [String; 4] {
+        ["--signing-key".into(), self.signing_key(), "--trusted-keys".into(), trusted.into()]
+    }
+}
+
+/// Give each of `nodes` a signing key and return a trusted keys file
+/// listing them all.
+fn trust_each_other(dir: &TempDir, nodes: &[&Node]) -> String {
+    let lines: Vec<String> = nodes.iter().map(|node| {
+        let output = node.run(&["keygen-signing", &node.signing_key()]);
+        String::from_utf8_lossy(&output.stdout).lines().last().unwrap().to_string()
+    }).collect();
+    let trusted = dir.file("trusted_keys");
+    fs::write(&trusted, lines.join("\n")).unwrap();
+    trusted
+}
+
+#[test]
+fn concurrent_invocations_are_not_lost() {
+    let dir = TempDir::new("cli-concurrent");
+    let node = Node { dir: &dir, id: "fake-web-01" };
+    let writers = 16;
+
+    thread::scope(|s| {
+        for i in 0..writers {
+            let node = &node;
+            s.spawn(move || node.run(&["visit", &format!("10.0.0.{}", i), "web-01"]));
+        }
+    });
+
+    let state = node.load();
+    assert_eq!(state.node_id, "fake-web-01");
+    assert_eq!(state.attackers.len(), writers);
+}
+
+#[test]
+fn two_nodes_share_a_host() {
+    let dir = TempDir::new("cli-two-nodes");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    db.run(&["cred", "root:toor"]);
+    assert!(web.load().stolen_creds.elements().is_empty());
+
+    db.run(&["merge", "--allow-unsigned", &web.state_file()]);
+    web.run(&["merge", "--allow-unsigned", &db.state_file()]);
+    let (web_state, db_state) = (web.load(), db.load());
+    assert_eq!(web_state.node_id, "fake-web-01");
+    assert_eq!(db_state.node_id, "fake-db-01");
+    assert_eq!(web_state.hash(), db_state.hash());
+    assert!(db_state.attackers.contains_key("10.0.0.5"));
+}
+
+#[test]
+fn flags_override_environment_override_config_file() {
+    let dir = TempDir::new("cli-precedence");
+    let config = dir.file("config.toml");
+    fs::write(&config, format!(
+        "node_id = \"from-file\"\nstate_file = \"{}\"\nlog_file = \"{}\"\n",
+        dir.file("file.state"), dir.file("helper.log"),
+    )).unwrap();
+    let cmd = || {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_maya-crdt"));
+        cmd.env("SYSLOGD_HELPER_CONFIG", &config);
+        cmd
+    };
+
+    let run = |cmd: &mut Command| assert!(cmd.output().unwrap().status.success());
+    run(cmd().args(["visit", "10.0.0.1", "web-01"]));
+    run(cmd().env("SYSLOGD_HELPER_STATE_FILE", dir.file("env.state")).args(["visit", "10.0.0.2", "web-01"]));
+    run(cmd()
+        .env("SYSLOGD_HELPER_STATE_FILE", dir.file("env.state"))
+        .args(["--state-file", &dir.file("flag.state"), "--node-id=from-flag", "visit", "10.0.0.3", "web-01"]));
+
+    for (file, node, ip) in [("file.state", "from-file", "10.0.0.1"), ("env.state", "from-file", "10.0.0.2"), ("flag.state", "from-flag", "10.0.0.3")] {
+        let state = MayaState::load(&dir.file(file), "unused").unwrap();
+        // Nothing is snapshotted yet, so the writer shows in the log's deltas
+        assert_eq!(state.version.get(node), 1, "{}", file);
+        assert_eq!(state.attackers.keys().collect::<Vec<_>>(), [ip], "{}", file);
+    }
+}
+
+#[test]
+fn bad_configuration_is_refused() {
+    let dir = TempDir::new("cli-bad-config");
+    let config = dir.file("config.toml");
+    fs::write(&config, "sync_interval = \"soon\"\n").unwrap();
+    let bin = env!("CARGO_BIN_EXE_maya-crdt");
+
+    let output = Command::new(bin).env("SYSLOGD_HELPER_CONFIG", &config).arg("stats").output().unwrap();
+    assert!(!output.status.success());
+    let output = Command::new(bin)
+        .env("SYSLOGD_HELPER_CONFIG", dir.file("absent.toml"))
+        .args(["--state-file", &dir.file("state"), "--sync-interval", "soon", "stats"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("sync_interval"));
+
+    // A listener would serve anyone who connects
+    let output = Command::new(bin)
+        .env("SYSLOGD_HELPER_CONFIG", dir.file("absent.toml"))
+        .args(["--state-file", &dir.file("state"), "--listen", "127.0.0.1:6514", "daemon"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs trusted_keys"));
+
+    // Members would only spread through anti-entropy
+    let output = Command::new(bin)
+        .env("SYSLOGD_HELPER_CONFIG", dir.file("absent.toml"))
+        .args(["--state-file", &dir.file("state"), "--sync-mode", "gossip", "--anti-entropy", "off", "stats"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("gossip\" needs anti_entropy"));
+}
+
+// Kills the daemon when the test ends, pass or fail
+struct Daemon(std::process::Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn daemons_sync_over_tcp() {
+    let dir = TempDir::new("cli-daemons");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+    let ports = [free_port(), free_port()];
+    let trusted = trust_each_other(&dir, &[&web, &db]);
+    let daemon = |node: &Node, port: u16, peer_port: u16| {
+        let peers = dir.file(&format!("{}.peers", node.id));
+        fs::write(&peers, format!("127.0.0.1:{}\n", peer_port)).unwrap();
+        let child = node.command()
+            .args(["--peers-file", &peers, "--auth-log", &dir.file("auth.log"), "--sync-interval", "1"])
+            .args(node.sync_keys(&trusted))
+            .args(["--listen", &format!("127.0.0.1:{}", port), "daemon"])
+            .spawn()
+            .unwrap();
+        Daemon(child)
+    };
+
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    db.run(&["cred", "root:toor"]);
+    let _web = daemon(&web, ports[0], ports[1]);
+    let _db = daemon(&db, ports[1], ports[0]);
+
+    let converged = (0..50).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        let (web_state, db_state) = (web.load(), db.load());
+        web_state.hash() == db_state.hash() && !web_state.stolen_creds.elements().is_empty()
+    });
+    assert!(converged, "daemons did not converge");
+    assert!(db.load().attackers.contains_key("10.0.0.5"));
+}
+
+#[test]
+fn merge_requires_a_trusted_signature() {
+    let dir = TempDir::new("cli-signed");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+    let (web_key, trusted) = (dir.file("web.key"), dir.file("trusted_keys"));
+
+    let output = web.run(&["keygen-signing", &web_key]);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let line = stdout.lines().last().unwrap();
+    assert!(line.starts_with("fake-web-01 "));
+    fs::write(&trusted, format!("{}\n", line)).unwrap();
+
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    let (signed, unsigned) = (dir.file("signed.out"), dir.file("unsigned.out"));
+    web.run(&["--signing-key", &web_key, "export", &signed]);
+    web.run(&["export", &unsigned]);
+
+    // Without keys unsigned data needs an opt-in; with a key of its own a
+    // node never takes it
+    let plain = db.command().args(["merge", &unsigned]).output().unwrap();
+    assert!(!plain.status.success());
+    assert!(String::from_utf8_lossy(&plain.stderr).contains("--allow-unsigned"));
+    let keyed = db.command().args(["--signing-key", &web_key, "merge", "--allow-unsigned", &unsigned]).output().unwrap();
+    assert!(!keyed.status.success());
+    assert!(db.load().attackers.is_empty());
+
+    let merge = |payload: &str| {
+        db.command().args(["--trusted-keys", &trusted, "merge", payload]).output().unwrap()
+    };
+    let refused = merge(&unsigned);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("not signed"));
+    assert!(db.load().attackers.is_empty());
+
+    assert!(merge(&signed).status.success());
+    assert!(db.load().attackers.contains_key("10.0.0.5"));
+    let log = fs::read_to_string(dir.file("fake-db-01.log")).unwrap();
+    assert!(log.contains("Rejected merge payload"));
+}
+
+#[test]
+fn daemon_pulls_what_it_missed() {
+    // web never pushes to db (no peers), as if db had been down when web
+    // recorded the visit; db's anti-entropy round pulls it anyway
+    let dir = TempDir::new("cli-pull");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+    let ports = [free_port(), free_port()];
+    let (web_peers, db_peers) = (dir.file("web.peers"), dir.file("db.peers"));
+    fs::write(&web_peers, "").unwrap();
+    fs::write(&db_peers, format!("127.0.0.1:{}\n", ports[0])).unwrap();
+
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    let trusted = trust_each_other(&dir, &[&web, &db]);
+    let daemon = |node: &Node, port: u16, peers: &str| {
+        let child = node.command()
+            .args(["--peers-file", peers, "--auth-log", &dir.file("auth.log"), "--sync-interval", "1"])
+            .args(node.sync_keys(&trusted))
+            .args(["--listen", &format!("127.0.0.1:{}", port), "--anti-entropy", "pull", "daemon"])
+            .spawn()
+            .unwrap();
+        Daemon(child)
+    };
+    let _web = daemon(&web, ports[0], &web_peers);
+    let _db = daemon(&db, ports[1], &db_peers);
+
+    let pulled = (0..50).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        db.load().attackers.contains_key("10.0.0.5")
+    });
+    assert!(pulled, "db did not pull from web");
+}
+
+#[test]
+fn gossip_joins_through_one_seed() {
+    // web is the seed; db and mail only list web, and learn of each other
+    // through the membership web gossips
+    let dir = TempDir::new("cli-gossip");
+    let nodes = [
+        Node { dir: &dir, id: "fake-web-01" },
+        Node { dir: &dir, id: "fake-db-01" },
+        Node { dir: &dir, id: "fake-mail-01" },
+    ];
+    let ports = [free_port(), free_port(), free_port()];
+    let seed = dir.file("seed.peers");
+    fs::write(&seed, format!("127.0.0.1:{}\n", ports[0])).unwrap();
+
+    nodes[1].run(&["visit", "10.0.0.9", "db-01"]);
+    let trusted = trust_each_other(&dir, &[&nodes[0], &nodes[1], &nodes[2]]);
+    let _daemons: Vec<_> = nodes.iter().zip(ports).map(|(node, port)| {
+        let child = node.command()
+            .args(["--peers-file", &seed, "--auth-log", &dir.file("auth.log"), "--sync-interval", "1"])
+            .args(node.sync_keys(&trusted))
+            .args(["--listen", &format!("127.0.0.1:{}", port), "--sync-mode", "gossip", "--fanout", "1", "daemon"])
+            .spawn()
+            .unwrap();
+        Daemon(child)
+    }).collect();
+
+    let joined = (0..75).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        let mail = nodes[2].load();
+        mail.members.len() == 3 && mail.attackers.contains_key("10.0.0.9")
+    });
+    assert!(joined, "mail did not learn the mesh through the seed");
+
+    let output = nodes[2].run(&["members"]);
+    let listed = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(listed.contains(&format!("fake-db-01 127.0.0.1:{}", ports[1])), "{}", listed);
+    assert!(listed.contains("fake-mail-01 127.0.0.1") && listed.contains("(this node)"), "{}", listed);
+}
+
+#[test]
+fn check_peers_shows_what_the_daemon_recorded() {
+    let dir = TempDir::new("cli-check-peers");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+    let ports = [free_port(), free_port()];
+    let dead = format!("127.0.0.1:{}", free_port());
+    let peers = dir.file("web.peers");
+    fs::write(&peers, format!("127.0.0.1:{}\n{}\n", ports[1], dead)).unwrap();
+    fs::write(dir.file("db.peers"), "").unwrap();
+
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    let trusted = trust_each_other(&dir, &[&web, &db]);
+    let daemon = |node: &Node, port: u16, peers: &str| {
+        let child = node.command()
+            .args(["--peers-file", peers, "--auth-log", &dir.file("auth.log"), "--sync-interval", "1"])
+            .args(node.sync_keys(&trusted))
+            .args(["--listen", &format!("127.0.0.1:{}", port), "daemon"])
+            .spawn()
+            .unwrap();
+        Daemon(child)
+    };
+    let _db = daemon(&db, ports[1], &dir.file("db.peers"));
+    let _web = daemon(&web, ports[0], &peers);
+
+    let table = |node: &Node| {
+        let output = node.run(&["--peers-file", &peers, "check-peers"]);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let recorded = (0..50).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        let table = table(&web);
+        table.lines().any(|line| line.contains("fake-db-01") && line.contains("up to date"))
+            && table.lines().any(|line| line.starts_with(&dead) && line.contains("Connection refused"))
+    });
+    assert!(recorded, "{}", table(&web));
+
+    // The dead peer is backed off rather than retried every cycle
+    let log = fs::read_to_string(dir.file("fake-web-01.log")).unwrap();
+    assert!(log.contains(&format!("Peer {} failed 1 time(s) in a row", dead)), "{}", log);
+}
+
+#[test]
+fn gossip_syncs_once_with_a_seed_named_by_hostname() {
+    let dir = TempDir::new("cli-gossip-dedupe");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let mut db = MayaState::new("fake-db-01");
+    db.announce("127.0.0.1:7001");
+    let db_file = dir.file("db.state");
+    db.save_as(&db_file, &Codec::default()).unwrap();
+    web.run(&["merge", "--allow-unsigned", &db_file]);
+
+    // db's listener by hostname, and our own by IP
+    let peers = dir.file("web.peers");
+    fs::write(&peers, "localhost:7001\n127.0.0.1:7000\n").unwrap();
+    let output = web.run(&["--peers-file", &peers, "--sync-mode", "gossip", "--advertise", "localhost:7000", "check-peers"]);
+    let table = String::from_utf8_lossy(&output.stdout).into_owned();
+    let rows: Vec<&str> = table.lines().skip(1).collect();
+    assert_eq!(rows.len(), 1, "{}", table);
+    assert!(rows[0].starts_with("localhost:7001 "), "{}", table);
+}
+
+#[test]
+fn ssh_fallback_records_no_version_for_the_peer() {
+    let dir = TempDir::new("cli-ssh-fallback");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let dead = format!("127.0.0.1:{}", free_port());
+    let peers = dir.file("web.peers");
+    fs::write(&peers, format!("{}\n", dead)).unwrap();
+    // scp and ssh that succeed without going anywhere
+    let bin = dir.file("bin");
+    fs::create_dir(&bin).unwrap();
+    for tool in ["scp", "ssh"] {
+        let path = format!("{}/{}", bin, tool);
+        fs::write(&path, "#!/bin/sh\nexit 0\n").unwrap();
+        fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    }
+
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    let _daemon = Daemon(web.command()
+        .env("PATH", format!("{}:{}", bin, std::env::var("PATH").unwrap()))
+        .args(["--peers-file", &peers, "--auth-log", &dir.file("auth.log"), "--sync-interval", "1"])
+        .args(["--ssh-fallback", "true", "daemon"])
+        .spawn()
+        .unwrap());
+
+    let records_file = format!("{}.peers", web.state_file());
+    let record = (0..25).find_map(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        PeerRecords::load(&records_file, None).get(&dead).filter(|record| record.last_success.is_some()).cloned()
+    });
+    let record = record.expect("the ssh push was not recorded");
+    // Nothing came back over ssh to say what the peer has
+    assert_eq!(record.version, VersionVector::new());
+    assert_eq!(record.node_id, None);
+    let output = web.run(&["--peers-file", &peers, "check-peers"]);
+    let table = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(table.lines().any(|line| line.starts_with(&dead) && line.contains("unknown")), "{}", table);
+
+    // Later cycles push what changed without calling the peer behind
+    web.run(&["visit", "10.0.0.6", "web-01"]);
+    let log = dir.file("fake-web-01.log");
+    let pushed = (0..25).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        fs::read_to_string(&log).unwrap().matches("Sync cycle complete: 1 successful").count() >= 2
+    });
+    assert!(pushed, "the second push never happened");
+    assert!(!fs::read_to_string(&log).unwrap().contains("is behind on updates"));
+}
+
+#[test]
+fn compact_refuses_while_a_peer_is_unidentified() {
+    let dir = TempDir::new("cli-compact");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let peers = dir.file("web.peers");
+    fs::write(&peers, "10.0.0.3:6514\n").unwrap();
+    web.run(&["cred", "root:toor"]);
+    web.run(&["uncred", "root:toor"]);
+
+    let compact = |args: &[&str]| {
+        let output = web.run(&[&["--peers-file", peers.as_str(), "compact"], args].concat());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let refused = compact(&[]);
+    assert!(refused.contains("Refusing to compact: peer 10.0.0.3:6514 has not reported"), "{}", refused);
+    assert_eq!(web.load().stolen_creds.tombstones(), 1);
+
+    assert!(compact(&["--force"]).contains("Compacted 1 tombstones"));
+    assert_eq!(web.load().stolen_creds.tombstones(), 0);
+}
+
+#[test]
+fn show_reports_only_real_location_conflicts() {
+    let dir = TempDir::new("cli-show");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+    let show = |node: &Node| String::from_utf8_lossy(&node.run(&["show"]).stdout).into_owned();
+
+    web.run(&["move", "10.0.0.5", "ssh"]);
+    db.run(&["move", "10.0.0.5", "ssh"]);
+    db.run(&["move", "10.0.0.9", "db-01"]);
+    web.run(&["move", "10.0.0.9", "web-01"]);
+    web.run(&["merge", "--allow-unsigned", &db.state_file()]);
+
+    let shown = show(&web);
+    assert!(!shown.contains("ssh and ssh"), "{}", shown);
+    let warnings: Vec<&str> = shown.lines().filter(|line| line.contains("seen simultaneously")).collect();
+    assert_eq!(warnings.len(), 1, "{}", shown);
+    assert!(warnings[0].contains("web-01") && warnings[0].contains("db-01"), "{}", shown);
+}
+
+#[test]
+fn show_lists_actions_in_the_merged_order() {
+    let dir = TempDir::new("cli-actions");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+
+    db.run(&["action", "10.0.0.5", "db-01", "mysql -u root"]);
+    web.run(&["merge", "--allow-unsigned", &db.state_file()]);
+    web.run(&["action", "10.0.0.5", "web-01", "cat /etc/passwd"]);
+    db.run(&["merge", "--allow-unsigned", &web.state_file()]);
+
+    let actions = |node: &Node| -> Vec<String> {
+        let shown = String::from_utf8_lossy(&node.run(&["show"]).stdout).into_owned();
+        let lines = shown.lines().skip_while(|line| line.trim() != "Actions:").skip(1);
+        lines.take_while(|line| line.starts_with("    [")).map(str::to_string).collect()
+    };
+    let web_actions = actions(&web);
+    assert_eq!(web_actions.len(), 2, "{:?}", web_actions);
+    assert!(web_actions[0].ends_with("by fake-db-01] db-01: mysql -u root"), "{:?}", web_actions);
+    assert!(web_actions[1].ends_with("by fake-web-01] web-01: cat /etc/passwd"), "{:?}", web_actions);
+    assert_eq!(actions(&db), web_actions);
+}
+
+#[test]
+fn sessions_open_expire_and_close() {
+    let dir = TempDir::new("cli-sessions");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+
+    // A TTL that is not whole seconds is refused, not silently dropped
+    let output = web.command().args(["session", "web-01", "sess-abc", "10m"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("session TTL must be whole seconds, got \"10m\""));
+    assert!(web.load().active_sessions.get(&"web-01".to_string()).is_none());
+
+    let stdout = web.run(&["session", "web-01", "sess-abc", "0"]).stdout;
+    assert!(String::from_utf8_lossy(&stdout).contains("expires in 0s"));
+    web.run(&["session", "db-01", "sess-def"]);
+    let stats = String::from_utf8_lossy(&web.run(&["stats"]).stdout).into_owned();
+    assert!(stats.contains("Sessions: 1"), "{}", stats);
+
+    web.run(&["session-close", "db-01"]);
+    assert!(web.load().active_sessions.get(&"db-01".to_string()).is_none());
+    let stdout = web.run(&["session-close", "db-01"]).stdout;
+    assert!(String::from_utf8_lossy(&stdout).contains("No open session on db-01"));
+}
+
+#[test]
+fn daemon_without_a_way_to_reach_its_peers_refuses_to_start() {
+    let dir = TempDir::new("cli-no-transport");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let peers = dir.file("web.peers");
+    fs::write(&peers, "10.0.0.3\n").unwrap();
+
+    let output = web.command()
+        .args(["--peers-file", &peers, "--auth-log", &dir.file("auth.log"), "daemon"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no transport reaches them"));
+}
+
+#[test]
+fn daemon_retries_after_a_failed_write() {
+    let dir = TempDir::new("cli-retry");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let auth_log = dir.file("auth.log");
+    fs::write(&auth_log, "sshd[812]: Accepted password for root from 10.0.0.7 port 52814 ssh2\n").unwrap();
+    // The op log cannot be opened for appending while a directory sits there
+    let oplog = format!("{}.oplog", web.state_file());
+    fs::create_dir(&oplog).unwrap();
+
+    let mut daemon = Daemon(web.command()
+        .args(["--peers-file", &dir.file("absent.peers"), "--auth-log", &auth_log, "--sync-interval", "1", "daemon"])
+        .spawn()
+        .unwrap());
+    let log = dir.file("fake-web-01.log");
+    let failed = (0..25).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        fs::read_to_string(&log).is_ok_and(|log| log.contains("failed, retrying next cycle"))
+    });
+    assert!(failed, "the write did not fail");
+    assert!(daemon.0.try_wait().unwrap().is_none(), "the daemon exited");
+
+    fs::remove_dir(&oplog).unwrap();
+    let detected = (0..25).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        web.load().attackers.contains_key("10.0.0.7")
+    });
+    assert!(detected, "the detection was not retried");
+}
+
+#[test]
+fn daemon_stops_instead_of_recovering_a_newer_state_file() {
+    let dir = TempDir::new("cli-newer");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    let mut daemon = Daemon(web.command()
+        .args(["--peers-file", &dir.file("absent.peers"), "--auth-log", &dir.file("auth.log"), "--sync-interval", "1", "daemon"])
+        .spawn()
+        .unwrap());
+    let log = dir.file("fake-web-01.log");
+    let started = (0..25).any(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        fs::read_to_string(&log).is_ok_and(|log| log.contains("Sync cycle 1 complete"))
+    });
+    assert!(started, "the daemon did not start");
+
+    // A newer version of the tool takes over the state file
+    let mut newer = web.load().to_versioned_json().unwrap();
+    newer["schema_version"] = (maya_crdt::SCHEMA_VERSION + 1).into();
+    fs::write(web.state_file(), newer.to_string()).unwrap();
+
+    let status = (0..25).find_map(|_| {
+        thread::sleep(std::time::Duration::from_millis(200));
+        daemon.0.try_wait().unwrap()
+    });
+    assert!(status.is_some_and(|status| !status.success()), "the daemon kept running");
+    assert_eq!(fs::read_to_string(web.state_file()).unwrap(), newer.to_string());
+    assert!(!fs::read_to_string(&log).unwrap().contains("restored"));
+}
+
+#[test]
+fn encryption_is_turned_on_with_encrypt() {
+    let dir = TempDir::new("cli-encrypt");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let key = dir.file("state.key");
+    web.run(&["visit", "10.0.0.5", "web-01"]);
+    web.run(&["keygen", &key]);
+
+    let keyed = |args: &[&str]| web.command().args(["--key-file", &key]).args(args).output().unwrap();
+    let refused = keyed(&["show"]);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("syslogd-helper encrypt"));
+
+    let sealed = keyed(&["encrypt"]);
+    assert!(sealed.status.success(), "{}", String::from_utf8_lossy(&sealed.stderr));
+    assert!(String::from_utf8_lossy(&sealed.stdout).contains(&format!("Sealed {}.oplog", web.state_file())));
+    let shown = String::from_utf8_lossy(&keyed(&["show"]).stdout).into_owned();
+    assert!(shown.contains("10.0.0.5"), "{}", shown);
+    assert!(String::from_utf8_lossy(&keyed(&["encrypt"]).stdout).contains("Nothing left in plaintext"));
+}
+
+#[test]
+fn show_json_prints_binary_and_sealed_state_as_json() {
+    let dir = TempDir::new("cli-show-json");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let key = dir.file("state.key");
+    web.run(&["keygen", &key]);
+    let sealed = |args: &[&str]| {
+        let output = web.command().args(["--key-file", &key, "--encoding", "binary"]).args(args).output().unwrap();
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        output
+    };
+    sealed(&["visit", "10.0.0.5", "web-01"]);
+    sealed(&["action", "10.0.0.5", "web-01", "cat /etc/shadow"]);
+
+    let state: MayaState = serde_json::from_slice(&sealed(&["show", "--json"]).stdout).unwrap();
+    assert_eq!(state.node_id, "fake-web-01");
+    assert_eq!(state.attackers["10.0.0.5"].actions.len(), 1);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_nodes_merge_and_convert_through_their_storage() {
+    let dir = TempDir::new("cli-sqlite");
+    let web = Node { dir: &dir, id: "fake-web-01" };
+    let db = Node { dir: &dir, id: "fake-db-01" };
+    let sqlite = |node: &Node, args: &[&str]| {
+        let output = node.command().args(["--storage", "sqlite"]).args(args).output().unwrap();
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    sqlite(&web, &["visit", "10.0.0.5", "web-01"]);
+    sqlite(&web, &["visit", "10.0.0.5", "web-01"]);
+    sqlite(&web, &["cred", "root:toor"]);
+    sqlite(&db, &["visit", "10.0.0.9", "db-01"]);
+    sqlite(&web, &["merge", "--allow-unsigned", &db.state_file()]);
+
+    // Each command read only its attacker, yet nothing was lost
+    let json = sqlite(&web, &["convert", "json", &web.state_file(), "-"]);
+    let state: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let attackers = state["attackers"].as_object().unwrap();
+    assert_eq!(attackers.keys().collect::<Vec<_>>(), ["10.0.0.5", "10.0.0.9"]);
+    let attacker: serde_json::Value = serde_json::from_str(&sqlite(&web, &["attacker", "10.0.0.5"])).unwrap();
+    assert_eq!(attacker["visits"]["web-01"]["counts"]["fake-web-01"], 2);
+    assert!(sqlite(&web, &["stats"]).contains("Attackers: 2"));
+
+    let missing = web.command().args(["--storage", "sqlite", "merge", "--allow-unsigned", &dir.file("absent.db")]).output().unwrap();
+    assert!(!missing.status.success());
+    assert!(!std::path::Path::new(&dir.file("absent.db")).exists());
+}
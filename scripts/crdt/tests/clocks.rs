@@ -0,0 +1,114 @@
+use maya_crdt::{now_millis, Causality, Crdt, HybridClock, MayaState, VersionVector, MAX_CLOCK_DRIFT};
+use std::time::Duration;
+
+fn vv(entries: &[(&str, u64)]) -> VersionVector {
+    let mut version = VersionVector::new();
+    for &(node, counter) in entries {
+        version.observe(node, counter);
+    }
+    version
+}
+
+#[test]
+fn equal_vectors_compare_equal() {
+    let a = vv(&[("fake-web-01", 3), ("fake-db-01", 1)]);
+    assert_eq!(a.compare(&a.clone()), Causality::Equal);
+    assert_eq!(VersionVector::new().compare(&VersionVector::new()), Causality::Equal);
+    // A zero entry is the same as no entry
+    assert_eq!(vv(&[("fake-web-01", 0)]).compare(&VersionVector::new()), Causality::Equal);
+}
+
+#[test]
+fn dominated_vectors_are_before_and_after() {
+    let older = vv(&[("fake-web-01", 2)]);
+    let newer = vv(&[("fake-web-01", 3), ("fake-db-01", 1)]);
+    assert_eq!(older.compare(&newer), Causality::Before);
+    assert_eq!(newer.compare(&older), Causality::After);
+    assert_eq!(VersionVector::new().compare(&older), Causality::Before);
+}
+
+#[test]
+fn diverged_vectors_are_concurrent() {
+    let web = vv(&[("fake-web-01", 3), ("fake-db-01", 1)]);
+    let db = vv(&[("fake-web-01", 2), ("fake-db-01", 2)]);
+    assert_eq!(web.compare(&db), Causality::Concurrent);
+    assert_eq!(db.compare(&web), Causality::Concurrent);
+}
+
+#[test]
+fn disjoint_keys_are_concurrent() {
+    let web = vv(&[("fake-web-01", 1)]);
+    let db = vv(&[("fake-db-01", 1)]);
+    assert_eq!(web.compare(&db), Causality::Concurrent);
+    assert_eq!(web.missing_from(&db), ["fake-web-01"]);
+}
+
+#[test]
+fn hlc_timestamps_only_move_forward() {
+    let mut clock = HybridClock::new();
+    let first = clock.tick_at("fake-web-01", 1_000);
+    let same_ms = clock.tick_at("fake-web-01", 1_000);
+    // The wall clock stepped back: the logical counter carries on
+    let stepped_back = clock.tick_at("fake-web-01", 900);
+    let later = clock.tick_at("fake-web-01", 2_000);
+
+    assert!(first < same_ms && same_ms < stepped_back && stepped_back < later);
+    assert_eq!((stepped_back.wall, stepped_back.logical), (1_000, 2));
+    assert_eq!((later.wall, later.logical), (2_000, 0));
+}
+
+#[test]
+fn hlc_merge_orders_later_events_after_the_peer() {
+    let mut web = HybridClock::new();
+    let mut db = HybridClock::new();
+    let remote = db.tick_at("fake-db-01", 5_000);
+    db.tick_at("fake-db-01", 5_000);
+
+    // web's wall clock lags db's, yet its next event sorts after db's
+    web.tick_at("fake-web-01", 4_000);
+    web.merge(&db);
+    let next = web.tick_at("fake-web-01", 4_100);
+    assert!(next > remote);
+    assert_eq!((next.wall, next.logical), (5_000, 2));
+
+    // Merging an older clock changes nothing
+    let before = web.clone();
+    web.merge(&HybridClock::new());
+    assert_eq!(web, before);
+}
+
+#[test]
+fn states_running_ahead_of_our_clock_are_refused() {
+    let now = now_millis();
+    let mut skewed = MayaState::new("fake-db-01");
+    skewed.hlc.wall = now + 3_600_000;
+    skewed.observe_visit("10.0.0.5", "db-01");
+    let err = skewed.check_drift(now, MAX_CLOCK_DRIFT).unwrap_err();
+    assert!(err.ahead >= Duration::from_secs(3_599), "{}", err);
+
+    // A forged timestamp counts even when the clock itself looks sane
+    let mut forged = MayaState::new("fake-db-01");
+    forged.add_session("web-01", "sess-1", None);
+    forged.active_sessions.entries.values_mut().for_each(|(_, ts)| ts.wall = u64::MAX);
+    assert!(forged.check_drift(now, MAX_CLOCK_DRIFT).is_err());
+
+    let mut honest = MayaState::new("fake-web-01");
+    honest.observe_visit("10.0.0.5", "web-01");
+    honest.hlc.wall += 60_000;
+    assert!(honest.check_drift(now, MAX_CLOCK_DRIFT).is_ok());
+}
+
+#[test]
+fn session_expiry_follows_physical_time() {
+    // Our clock was pushed to the end of time before drift was checked
+    let mut state = MayaState::new("fake-web-01");
+    let mut skewed = MayaState::new("fake-db-01");
+    skewed.hlc.wall = u64::MAX - 1;
+    state.merge(&skewed);
+
+    let ttl = Duration::from_secs(600);
+    let before = now_millis();
+    state.add_session("web-01", "sess-1", Some(ttl));
+    let expires_at = state.active_sessions.get(&"web-01".to_string()).unwrap().expires_at.unwrap();
+    assert!(expires_at >= before + 600_000 && expires_at <= now_millis() + 600_000);
+}
@@ -0,0 +1,56 @@
+use maya_crdt::{Crdt, MayaState};
+
+#[test]
+fn replicas_with_the_same_data_hash_the_same() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    web.observe_visit("10.0.0.5", "web-01");
+    web.add_cred("root:toor");
+    db.record_action("10.0.0.5", "db-01", "mysql -u root");
+    db.add_session("db-01", "sess-def", None);
+    assert_ne!(web.hash(), db.hash());
+
+    let (web_before, db_before) = (web.clone(), db.clone());
+    web.merge(&db_before);
+    db.merge(&web_before);
+
+    // Node ids, clocks and knowledge still differ; the data does not
+    assert_ne!(web.node_id, db.node_id);
+    assert_ne!(web.knowledge, db.knowledge);
+    assert_eq!(web.hash(), db.hash());
+}
+
+#[test]
+fn compacting_tombstones_keeps_the_hash() {
+    let mut state = MayaState::new("fake-web-01");
+    state.add_cred("root:toor");
+    state.add_cred("admin:admin");
+    state.remove_cred("admin:admin");
+    let before = state.hash();
+
+    assert_eq!(state.compact(), 1);
+    assert_eq!(state.hash(), before);
+}
+
+#[test]
+fn any_data_change_changes_the_hash() {
+    let mut state = MayaState::new("fake-web-01");
+    let mut seen = vec![state.hash()];
+    state.observe_visit("10.0.0.5", "web-01");
+    seen.push(state.hash());
+    state.record_auth_failure("10.0.0.5");
+    seen.push(state.hash());
+    state.add_cred("root:toor");
+    seen.push(state.hash());
+    state.add_session("web-01", "sess-abc", None);
+    seen.push(state.hash());
+
+    let mut unique = seen.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), seen.len());
+
+    // Closed sessions are tombstones, not data
+    state.close_session("web-01");
+    assert_eq!(state.hash(), seen[seen.len() - 2]);
+}
@@ -0,0 +1,63 @@
+use maya_crdt::{Crdt, MayaState, PNCounter};
+
+#[test]
+fn increments_from_separate_sensors_sum_after_merge() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    for _ in 0..3 {
+        web.observe_visit("10.0.0.5", "web-01");
+        web.record_auth_failure("10.0.0.5");
+    }
+    web.record_action("10.0.0.5", "web-01", "id");
+    for _ in 0..2 {
+        db.observe_visit("10.0.0.5", "db-01");
+        db.observe_visit("10.0.0.5", "web-01");
+        db.record_auth_failure("10.0.0.5");
+        db.record_action("10.0.0.5", "db-01", "mysql -u root");
+    }
+
+    web.merge(&db);
+    db.merge(&web);
+    // Merging again must not count anything twice
+    web.merge(&db);
+
+    for state in [&web, &db] {
+        let attacker = &state.attackers["10.0.0.5"];
+        assert_eq!(attacker.visits["web-01"].value(), 5);
+        assert_eq!(attacker.visits["db-01"].value(), 2);
+        assert_eq!(attacker.total_visits(), 7);
+        assert_eq!(attacker.action_count.value(), 3);
+        assert_eq!(attacker.auth_failures.value(), 5);
+    }
+}
+
+#[test]
+fn deltas_carry_increments_without_double_counting() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    let first = web.record_auth_failure("10.0.0.5");
+    let second = web.record_auth_failure("10.0.0.5");
+
+    // Out of order and repeated, as a retried push would deliver them
+    db.merge(&second);
+    db.merge(&first);
+    db.merge(&second);
+    db.record_auth_failure("10.0.0.5");
+    assert_eq!(db.attackers["10.0.0.5"].auth_failures.value(), 3);
+}
+
+#[test]
+fn pn_counter_decrements_from_separate_sensors_sum_after_merge() {
+    let mut web = PNCounter::new();
+    let mut db = PNCounter::new();
+    web.increment("fake-web-01", 2);
+    web.decrement("fake-web-01", 3);
+    db.increment("fake-db-01", 1);
+    db.decrement("fake-db-01", 1);
+
+    web.merge(&db);
+    db.merge(&web);
+    web.merge(&db);
+    assert_eq!(web, db);
+    assert_eq!(web.value(), -1);
+}
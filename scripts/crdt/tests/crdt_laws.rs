@@ -0,0 +1,208 @@
+//! Property tests for the join-semilattice laws every `Crdt` must obey:
+//! merge is commutative, associative and idempotent, bottom is its
+//! identity, and both inputs are ≤ their merge.
+//!
+//! Replica states are built the way they arise in practice: three replicas
+//! apply random local operations and merge each other in random order.
+
+use maya_crdt::*;
+use proptest::prelude::*;
+use std::fmt::Debug;
+use std::time::Duration;
+
+const NODES: [&str; 3] = ["node-a", "node-b", "node-c"];
+
+#[derive(Debug, Clone)]
+enum Op {
+    /// Replica, operation kind, argument
+    Local(usize, u8, u8),
+    /// Replica merges another replica's state
+    Sync(usize, usize),
+}
+
+fn ops() -> impl Strategy<Value = Vec<Op>> {
+    let op = prop_oneof![
+        3 => (0..3usize, any::<u8>(), 0..4u8).prop_map(|(r, k, a)| Op::Local(r, k, a)),
+        1 => (0..3usize, 0..3usize).prop_map(|(r, from)| Op::Sync(r, from)),
+    ];
+    prop::collection::vec(op, 0..40)
+}
+
+/// Run `ops` over three replicas. `local` applies one operation at a
+/// replica, given the replica index, a per-replica tick, kind and argument.
+fn simulate<T: Crdt + Clone>(ops: &[Op], mut local: impl FnMut(&mut T, usize, u64, u8, u8)) -> [T; 3] {
+    let mut replicas = [T::bottom(), T::bottom(), T::bottom()];
+    let mut ticks = [0u64; 3];
+    for op in ops {
+        match *op {
+            Op::Local(r, kind, arg) => {
+                ticks[r] += 1;
+                local(&mut replicas[r], r, ticks[r], kind, arg);
+            }
+            Op::Sync(r, from) => {
+                let remote = replicas[from].clone();
+                replicas[r].merge(&remote);
+            }
+        }
+    }
+    replicas
+}
+
+fn join<T: Crdt + Clone>(a: &T, b: &T) -> T {
+    let mut out = a.clone();
+    out.merge(b);
+    out
+}
+
+fn check_laws<T, K>(a: &T, b: &T, c: &T, key: impl Fn(&T) -> K) -> Result<(), TestCaseError>
+where
+    T: Crdt + Clone,
+    K: PartialEq + Debug,
+{
+    prop_assert_eq!(key(&join(a, b)), key(&join(b, a)), "commutativity");
+    prop_assert_eq!(key(&join(&join(a, b), c)), key(&join(a, &join(b, c))), "associativity");
+    prop_assert_eq!(key(&join(a, a)), key(a), "idempotence");
+    prop_assert_eq!(key(&join(a, &T::bottom())), key(a), "bottom is identity");
+    prop_assert!(a.leq(&join(a, b)) && b.leq(&join(a, b)), "inputs are below their merge");
+    prop_assert!(T::bottom().leq(a), "bottom is below everything");
+    Ok(())
+}
+
+fn laws<T: Crdt + Clone + PartialEq + Debug>(r: &[T; 3]) -> Result<(), TestCaseError> {
+    check_laws(&r[0], &r[1], &r[2], |x| x.clone())
+}
+
+fn ts(replica: usize, tick: u64) -> HybridTimestamp {
+    HybridTimestamp { wall: tick, logical: 0, node: NODES[replica].to_string() }
+}
+
+/// MayaState without the parts that depend on where a merge happened.
+fn replicated(state: &MayaState) -> MayaState {
+    let mut state = state.clone();
+    state.node_id.clear();
+    state.clock.node_id.clear();
+    state.knowledge.clear();
+    state
+}
+
+proptest! {
+    #[test]
+    fn gset(ops in ops()) {
+        laws(&simulate::<GSet<u8>>(&ops, |s, _, _, _, a| s.add(a)))?;
+    }
+
+    #[test]
+    fn gcounter(ops in ops()) {
+        laws(&simulate::<GCounter>(&ops, |s, r, _, _, a| s.increment(NODES[r], a as u64)))?;
+    }
+
+    #[test]
+    fn pncounter(ops in ops()) {
+        laws(&simulate::<PNCounter>(&ops, |s, r, _, k, a| {
+            if k % 2 == 0 {
+                s.increment(NODES[r], a as u64);
+            } else {
+                s.decrement(NODES[r], a as u64);
+            }
+        }))?;
+    }
+
+    #[test]
+    fn version_vector(ops in ops()) {
+        laws(&simulate::<VersionVector>(&ops, |s, r, t, _, _| s.observe(NODES[r], t)))?;
+    }
+
+    #[test]
+    fn gseq(ops in ops()) {
+        laws(&simulate::<GSeq<u8>>(&ops, |s, r, t, _, a| s.append(ts(r, t), a)))?;
+    }
+
+    #[test]
+    fn aworset(ops in ops()) {
+        laws(&simulate::<AWORSet<u8>>(&ops, |s, r, t, k, a| {
+            let dot = (NODES[r].to_string(), t);
+            if k % 3 == 0 {
+                s.remove(&a, dot);
+            } else {
+                s.add(a, dot);
+            }
+        }))?;
+    }
+
+    #[test]
+    fn lww_register(ops in ops()) {
+        laws(&simulate::<LWWRegister<u8>>(&ops, |s, r, t, _, a| s.set(a, ts(r, t))))?;
+    }
+
+    #[test]
+    fn lww_map(ops in ops()) {
+        laws(&simulate::<LWWMap<u8, u8>>(&ops, |s, r, t, k, a| {
+            if k % 3 == 0 {
+                s.remove(a, ts(r, t));
+            } else {
+                s.insert(a, k, ts(r, t));
+            }
+        }))?;
+    }
+
+    #[test]
+    fn mv_register(ops in ops()) {
+        laws(&simulate::<MVRegister<u8>>(&ops, |s, r, t, _, a| {
+            s.set(a, (NODES[r].to_string(), t), ts(r, t));
+        }))?;
+    }
+
+    #[test]
+    fn attacker_state(ops in ops()) {
+        let states = simulate::<MayaState>(&ops, |s, r, _, k, a| {
+            s.node_id = NODES[r].to_string();
+            apply_domain_op(s, k, a);
+        });
+        let attackers = states.map(|s| s.attackers.get("10.0.0.0").cloned().unwrap_or_default());
+        laws(&attackers)?;
+    }
+
+    #[test]
+    fn maya_state(ops in ops()) {
+        let states = simulate::<MayaState>(&ops, |s, r, _, k, a| {
+            s.node_id = NODES[r].to_string();
+            apply_domain_op(s, k, a);
+        });
+        check_laws(&states[0], &states[1], &states[2], replicated)?;
+    }
+}
+
+fn apply_domain_op(state: &mut MayaState, kind: u8, arg: u8) {
+    let ip = format!("10.0.0.{}", arg % 2);
+    let decoy = format!("decoy-{}", arg);
+    let cred = format!("user{}:pass", arg);
+    match kind % 11 {
+        0 => { state.observe_visit(&ip, &decoy); }
+        1 => { state.record_action(&ip, &decoy, "ls"); }
+        2 => { state.update_location(&ip, &decoy); }
+        3 => { state.add_cred(&cred); }
+        4 => { state.remove_cred(&cred); }
+        5 => { state.add_session(&decoy, &ip, None); }
+        6 => { state.add_session(&decoy, &ip, Some(Duration::from_secs(600))); }
+        7 => { state.close_session(&decoy); }
+        8 => { state.announce(&format!("10.0.1.{}:6514", arg)); }
+        9 => { state.forget_member(NODES[arg as usize % 3]); }
+        _ => { state.record_auth_failure(&ip); }
+    }
+}
+
+proptest! {
+    #[test]
+    fn content_hash_converges(ops in ops()) {
+        let [a, b, c] = simulate::<MayaState>(&ops, |s, r, _, k, a| {
+            s.node_id = NODES[r].to_string();
+            apply_domain_op(s, k, a);
+        });
+        // Every replica ends up with everything, merged in different orders
+        let abc = join(&join(&a, &b), &c);
+        let cba = join(&join(&c, &b), &a);
+        let bca = join(&join(&b, &c), &a);
+        prop_assert_eq!(abc.hash(), cba.hash());
+        prop_assert_eq!(abc.hash(), bca.hash());
+    }
+}
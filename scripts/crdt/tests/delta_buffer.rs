@@ -0,0 +1,94 @@
+use maya_crdt::{Causality, Crdt, DeltaBuffer, MayaState, Pending, VersionVector};
+
+const WEB: &str = "10.0.0.2:6514";
+const DB: &str = "10.0.0.3:6514";
+
+// A buffer holding three deltas from one node, and the state they built
+fn buffered() -> (DeltaBuffer, MayaState) {
+    let mut state = MayaState::new("fake-mail-01");
+    let mut buffer = DeltaBuffer::default();
+    buffer.push(state.observe_visit("10.0.0.5", "mail-01"));
+    buffer.push(state.add_cred("root:toor"));
+    buffer.push(state.observe_visit("10.0.0.9", "mail-01"));
+    (buffer, state)
+}
+
+#[test]
+fn new_peers_get_the_full_state() {
+    let (buffer, _) = buffered();
+    assert!(matches!(buffer.pending_for(WEB), Pending::Full(3)));
+}
+
+#[test]
+fn acked_deltas_stop_being_sent() {
+    let (mut buffer, state) = buffered();
+    buffer.ack(WEB, 3);
+    assert!(matches!(buffer.pending_for(WEB), Pending::UpToDate));
+
+    let mut state = state;
+    buffer.push(state.record_action("10.0.0.5", "mail-01", "id"));
+    let Pending::Delta(delta, seq) = buffer.pending_for(WEB) else { panic!("expected a delta") };
+    assert_eq!(seq, 4);
+    // Only the new action, not the visits the peer already acked
+    assert_eq!(delta.attackers.keys().collect::<Vec<_>>(), ["10.0.0.5"]);
+    assert!(delta.stolen_creds.elements().is_empty());
+}
+
+#[test]
+fn unacked_deltas_are_kept_per_peer() {
+    let (mut buffer, state) = buffered();
+    buffer.ack(WEB, 3);
+    buffer.ack(DB, 1);
+
+    let Pending::Delta(delta, seq) = buffer.pending_for(DB) else { panic!("expected a delta") };
+    assert_eq!(seq, 3);
+    assert_eq!(delta.attackers.keys().collect::<Vec<_>>(), ["10.0.0.9"]);
+    assert!(delta.stolen_creds.elements().contains("root:toor"));
+
+    // What db missed plus what it has is everything
+    let mut db = MayaState::new("fake-db-01");
+    db.merge(&buffer.deltas[&1]);
+    db.merge(&delta);
+    assert_eq!(db.hash(), state.hash());
+}
+
+#[test]
+fn prune_drops_what_every_peer_acked() {
+    let (mut buffer, _) = buffered();
+    buffer.ack(WEB, 3);
+    buffer.ack(DB, 2);
+    let peers = [WEB.to_string(), DB.to_string()];
+
+    buffer.prune(&peers);
+    assert_eq!(buffer.deltas.keys().copied().collect::<Vec<_>>(), [3]);
+
+    buffer.ack(DB, 3);
+    buffer.prune(&peers);
+    assert!(buffer.deltas.is_empty());
+    assert!(matches!(buffer.pending_for(DB), Pending::UpToDate));
+}
+
+#[test]
+fn unlisted_peers_are_forgotten_and_stop_holding_deltas_back() {
+    let (mut buffer, _) = buffered();
+    buffer.ack(WEB, 3);
+    buffer.ack(DB, 1);
+
+    buffer.prune(&[WEB.to_string()]);
+    assert!(buffer.deltas.is_empty());
+    assert!(!buffer.acked.contains_key(DB));
+    // Listed again later, db gets the full state
+    assert!(matches!(buffer.pending_for(DB), Pending::Full(3)));
+}
+
+#[test]
+fn an_empty_ack_version_leaves_the_peer_unknown() {
+    let (mut buffer, state) = buffered();
+    // What an ssh push reports: it got through, nothing about what it has
+    buffer.observe_peer(WEB, &VersionVector::new());
+    assert!(buffer.peer_versions.is_empty());
+    assert_eq!(buffer.peer_causality(WEB, &state.version), None);
+
+    buffer.observe_peer(WEB, &state.version);
+    assert_eq!(buffer.peer_causality(WEB, &state.version), Some(Causality::Equal));
+}
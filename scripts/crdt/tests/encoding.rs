@@ -0,0 +1,101 @@
+use maya_crdt::config::Config;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use maya_crdt::{Codec, Encoding, MayaState, BINARY_MAGIC, MAX_DECODED};
+use std::fs;
+use std::io::Write;
+
+mod common;
+use common::TempDir;
+
+fn sample() -> MayaState {
+    let mut state = MayaState::new("fake-web-01");
+    for i in 0..20 {
+        let ip = format!("10.0.0.{}", i);
+        state.observe_visit(&ip, "web-01");
+        state.record_action(&ip, "web-01", "cat /etc/passwd");
+        state.update_location(&ip, "db-01");
+        state.add_cred(&format!("user{}:hunter2", i));
+    }
+    state.add_session("web-01", "sess-abc", None);
+    state
+}
+
+#[test]
+fn binary_round_trips_and_is_detected() {
+    let dir = TempDir::new("binary");
+    let path = dir.file("state");
+    let state = sample();
+
+    state.save_as(&path, &Codec::from(Encoding::Binary)).unwrap();
+    let data = fs::read(&path).unwrap();
+    assert!(data.starts_with(BINARY_MAGIC));
+    assert_eq!(Encoding::detect(&data), Encoding::Binary);
+    assert_eq!(MayaState::load(&path, "fake-web-01").unwrap(), state);
+}
+
+#[test]
+fn binary_is_smaller_than_json() {
+    let state = sample();
+    let json = state.encode(&Codec::from(Encoding::Json)).unwrap();
+    let binary = state.encode(&Codec::from(Encoding::Binary)).unwrap();
+    assert!(binary.len() * 5 < json.len(), "binary {} bytes vs json {}", binary.len(), json.len());
+}
+
+#[test]
+fn converting_back_to_json_keeps_everything() {
+    let dir = TempDir::new("convert");
+    let path = dir.file("state");
+    let state = sample();
+
+    state.save_as(&path, &Codec::from(Encoding::Binary)).unwrap();
+    MayaState::load(&path, "fake-web-01").unwrap().save_as(&path, &Codec::default()).unwrap();
+
+    let data = fs::read(&path).unwrap();
+    assert_eq!(Encoding::detect(&data), Encoding::Json);
+    assert!(serde_json::from_slice::<serde_json::Value>(&data).is_ok());
+    assert_eq!(MayaState::load(&path, "fake-web-01").unwrap(), state);
+}
+
+#[test]
+fn recover_keeps_the_backup_encoding() {
+    let dir = TempDir::new("recover-binary");
+    let path = dir.file("state");
+    let state = sample();
+
+    state.save_as(&path, &Codec::from(Encoding::Binary)).unwrap();
+    state.save_as(&path, &Codec::from(Encoding::Binary)).unwrap();
+    fs::write(&path, b"MAYA\x01 truncated").unwrap();
+    assert!(MayaState::load(&path, "fake-web-01").is_err());
+
+    let (recovered, _) = MayaState::recover(&path, &Codec::default()).unwrap();
+    assert_eq!(recovered, state);
+    assert_eq!(Encoding::detect(&fs::read(&path).unwrap()), Encoding::Binary);
+}
+
+#[test]
+fn payloads_that_inflate_too_far_are_refused() {
+    // A few hundred KiB that would inflate past MAX_DECODED
+    let mut bomb = DeflateEncoder::new(BINARY_MAGIC.to_vec(), Compression::fast());
+    let zeros = vec![0u8; 1 << 20];
+    for _ in 0..=(MAX_DECODED >> 20) {
+        bomb.write_all(&zeros).unwrap();
+    }
+    let bomb = bomb.finish().unwrap();
+    assert!(bomb.len() < 1 << 20);
+
+    let err = MayaState::decode(&bomb, &Codec::default()).unwrap_err();
+    assert!(err.to_string().contains("inflates to more than"), "{}", err);
+}
+
+#[test]
+fn config_selects_encoding() {
+    let dir = TempDir::new("config");
+    let path = dir.file("config.toml");
+
+    assert_eq!(Config::load(&path).unwrap(), Config::default());
+    fs::write(&path, "# compact files\nencoding = \"binary\"\n").unwrap();
+    assert_eq!(Config::load(&path).unwrap().encoding, Encoding::Binary);
+    fs::write(&path, "encoding = \"xml\"\n").unwrap();
+    assert!(Config::load(&path).is_err());
+}
@@ -0,0 +1,189 @@
+use maya_crdt::crypto::{is_sealed, StateKey};
+use maya_crdt::{oplog_path, read_oplog, Codec, Crdt, DeltaBuffer, Encoding, LoadError, MayaState};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+mod common;
+use common::TempDir;
+
+fn sealed(encoding: Encoding) -> Codec {
+    Codec { encoding, key: Some(StateKey::generate()) }
+}
+
+fn sample() -> MayaState {
+    let mut state = MayaState::new("fake-web-01");
+    state.observe_visit("10.0.0.5", "web-01");
+    state.add_cred("root:toor");
+    state
+}
+
+#[test]
+fn sealed_state_round_trips_without_plaintext_on_disk() {
+    for encoding in [Encoding::Json, Encoding::Binary] {
+        let dir = TempDir::new(&format!("sealed-{}", encoding));
+        let path = dir.file("state");
+        let codec = sealed(encoding);
+        let state = sample();
+
+        state.save_as(&path, &codec).unwrap();
+        let data = fs::read(&path).unwrap();
+        assert!(is_sealed(&data));
+        let text = String::from_utf8_lossy(&data);
+        assert!(!text.contains("root:toor") && !text.contains("10.0.0.5"));
+
+        assert_eq!(MayaState::load_with(&path, "fake-web-01", &codec).unwrap(), state);
+    }
+}
+
+#[test]
+fn sealed_state_is_left_alone_without_the_right_key() {
+    let dir = TempDir::new("sealed-wrong-key");
+    let path = dir.file("state");
+    sample().save_as(&path, &sealed(Encoding::Json)).unwrap();
+    let before = fs::read(&path).unwrap();
+
+    let err = MayaState::load(&path, "fake-web-01").unwrap_err();
+    assert!(matches!(err, LoadError::Encrypted));
+    let err = MayaState::load_with(&path, "fake-web-01", &sealed(Encoding::Json)).unwrap_err();
+    assert!(matches!(err, LoadError::Decrypt));
+
+    // Not quarantined as corrupt: the file is fine, the key is not
+    assert_eq!(fs::read(&path).unwrap(), before);
+}
+
+#[test]
+fn tampering_is_detected() {
+    let dir = TempDir::new("sealed-tamper");
+    let path = dir.file("state");
+    let codec = sealed(Encoding::Json);
+    sample().save_as(&path, &codec).unwrap();
+
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    fs::write(&path, &data).unwrap();
+    assert!(matches!(MayaState::load_with(&path, "fake-web-01", &codec), Err(LoadError::Decrypt)));
+}
+
+#[test]
+fn plaintext_is_refused_until_sealed() {
+    let dir = TempDir::new("sealed-upgrade");
+    let path = dir.file("state");
+    let plain = Codec::default();
+    let codec = sealed(Encoding::Json);
+    let key = codec.key.as_ref().unwrap();
+
+    // A backup, an old op log generation and a current one, all plaintext
+    let mut state = sample();
+    state.save(&path).unwrap();
+    let delta = state.add_cred("admin:admin123");
+    state.commit(&path, "cred", &delta, &plain).unwrap();
+    state.snapshot(&path, &plain).unwrap();
+    let delta = state.observe_visit("10.0.0.9", "db-01");
+    state.commit(&path, "visit", &delta, &plain).unwrap();
+    let before = fs::read(&path).unwrap();
+
+    let err = MayaState::load_with(&path, "fake-web-01", &codec).unwrap_err();
+    assert!(matches!(err, LoadError::Plaintext), "{}", err);
+    assert_eq!(fs::read(&path).unwrap(), before);
+
+    let mut files = maya_crdt::seal_plaintext(&path, key).unwrap();
+    files.sort();
+    let log = oplog_path(&path);
+    assert_eq!(files, [path.clone(), format!("{}.bak.1", path), log.clone(), format!("{}.1", log)]);
+    for file in &files {
+        let data = String::from_utf8_lossy(&fs::read(file).unwrap()).into_owned();
+        assert!(!data.contains("root:toor") && !data.contains("10.0.0.9"), "{} is still readable", file);
+    }
+    assert_eq!(MayaState::load_with(&path, "fake-web-01", &codec).unwrap(), state);
+    assert!(maya_crdt::seal_plaintext(&path, key).unwrap().is_empty());
+
+    // A plaintext entry appended afterwards is refused, never replayed
+    let mut forged = MayaState::new("fake-db-01");
+    let injected = forged.add_cred("forged:cred");
+    forged.commit(&path, "cred", &injected, &plain).unwrap();
+    let err = MayaState::load_with(&path, "fake-web-01", &codec).unwrap_err();
+    assert!(matches!(err, LoadError::Plaintext), "{}", err);
+    assert!(read_oplog(&log, Some(key)).iter().all(|logged| !logged.delta.stolen_creds.elements().contains("forged:cred")));
+}
+
+#[test]
+fn plaintext_buffers_load_empty_with_a_key() {
+    let dir = TempDir::new("sealed-buffer-upgrade");
+    let path = dir.file("delta");
+    let key = StateKey::generate();
+    let mut buffer = DeltaBuffer::default();
+    buffer.push(sample());
+    buffer.save(&path, None).unwrap();
+    assert!(DeltaBuffer::load(&path, Some(&key)).deltas.is_empty());
+
+    assert!(maya_crdt::seal_file(&path, &key).unwrap());
+    assert_eq!(DeltaBuffer::load(&path, Some(&key)).deltas.len(), 1);
+}
+
+#[test]
+fn op_log_and_delta_buffer_are_sealed() {
+    let dir = TempDir::new("sealed-log");
+    let path = dir.file("state");
+    let codec = sealed(Encoding::Binary);
+    let key = codec.key.as_ref();
+
+    let mut state = MayaState::new("fake-web-01");
+    let delta = state.add_cred("root:toor");
+    state.commit(&path, "cred", &delta, &codec).unwrap();
+    let log = fs::read_to_string(oplog_path(&path)).unwrap();
+    assert!(!log.contains("root:toor"));
+    assert_eq!(read_oplog(&oplog_path(&path), key).len(), 1);
+    assert!(read_oplog(&oplog_path(&path), None).is_empty());
+    assert_eq!(MayaState::load_with(&path, "fake-web-01", &codec).unwrap(), state);
+
+    let buffer_path = dir.file("delta");
+    let mut buffer = DeltaBuffer::default();
+    buffer.push(delta);
+    buffer.save(&buffer_path, key).unwrap();
+    assert!(is_sealed(&fs::read(&buffer_path).unwrap()));
+    assert_eq!(DeltaBuffer::load(&buffer_path, key).deltas.len(), 1);
+}
+
+#[test]
+fn sealed_sync_payload_merges() {
+    let dir = TempDir::new("sealed-payload");
+    let payload_path = dir.file("maya.state");
+    let key = StateKey::generate();
+    let sender = Codec { encoding: Encoding::Binary, key: Some(key.clone()) };
+    let receiver = Codec { encoding: Encoding::Json, key: Some(key) };
+
+    let mut remote = MayaState::new("fake-db-01");
+    let delta = remote.add_cred("root:toor");
+    fs::write(&payload_path, delta.encode(&sender).unwrap()).unwrap();
+
+    let payload = MayaState::load_with(&payload_path, "fake-web-01", &receiver).unwrap();
+    let mut local = MayaState::new("fake-web-01");
+    local.merge(&payload);
+    assert!(local.stolen_creds.elements().contains("root:toor"));
+}
+
+#[test]
+fn key_file_must_be_private() {
+    let dir = TempDir::new("keyfile");
+    let path = dir.file("state.key");
+    let key = StateKey::generate();
+    key.save(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // The loaded key opens what the original sealed
+    let sealed = key.seal(b"attacker data");
+    assert_eq!(StateKey::load(&path).unwrap().open(&sealed).unwrap(), b"attacker data");
+
+    // Never overwrites an existing key
+    assert!(StateKey::generate().save(&path).is_err());
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(StateKey::load(&path).is_err());
+
+    // Whoever owns the file could swap the key
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    std::os::unix::fs::chown(&path, Some(1000), None).unwrap();
+    let err = StateKey::load(&path).unwrap_err();
+    assert!(err.to_string().contains("not root"), "{}", err);
+}
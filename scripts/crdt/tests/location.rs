@@ -0,0 +1,46 @@
+use maya_crdt::{Crdt, MayaState};
+
+fn location(state: &MayaState) -> Option<Vec<String>> {
+    let attacker = &state.attackers["10.0.0.5"];
+    attacker.location.conflicts().map(|values| values.into_iter().cloned().collect())
+}
+
+#[test]
+fn concurrent_moves_to_different_decoys_conflict() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    web.update_location("10.0.0.5", "web-01");
+    db.update_location("10.0.0.5", "db-01");
+    web.merge(&db);
+
+    let mut seen = location(&web).unwrap();
+    seen.sort();
+    assert_eq!(seen, ["db-01", "web-01"]);
+}
+
+#[test]
+fn concurrent_moves_to_the_same_place_agree() {
+    // Every daemon's auth.log detection writes "ssh"
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    web.update_location("10.0.0.5", "ssh");
+    db.update_location("10.0.0.5", "ssh");
+    web.merge(&db);
+
+    assert_eq!(web.attackers["10.0.0.5"].location.values().len(), 2);
+    assert_eq!(location(&web), None);
+}
+
+#[test]
+fn a_later_move_resolves_the_conflict() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    web.update_location("10.0.0.5", "web-01");
+    db.update_location("10.0.0.5", "db-01");
+    web.merge(&db);
+
+    web.update_location("10.0.0.5", "mail-01");
+    db.merge(&web);
+    assert_eq!(location(&db), None);
+    assert_eq!(db.attackers["10.0.0.5"].location.values(), [&"mail-01".to_string()]);
+}
@@ -0,0 +1,49 @@
+use maya_crdt::{Crdt, MayaState};
+
+#[test]
+fn joining_through_one_seed_learns_every_member() {
+    let mut seed = MayaState::new("fake-web-01");
+    seed.announce("10.0.0.1:6514");
+    let mut db = MayaState::new("fake-db-01");
+    db.announce("10.0.0.2:6514");
+    seed.merge(&db);
+
+    // A new decoy only knows the seed, and learns db through it
+    let mut mail = MayaState::new("fake-mail-01");
+    let joined = mail.announce("10.0.0.3:6514");
+    seed.merge(&joined);
+    mail.merge(&seed);
+
+    let peers: Vec<_> = mail.peers().map(|(node, addr)| (node.as_str(), addr.as_str())).collect();
+    assert_eq!(peers, [("fake-db-01", "10.0.0.2:6514"), ("fake-web-01", "10.0.0.1:6514")]);
+    assert_eq!(seed.members.len(), 3);
+}
+
+#[test]
+fn newer_announcement_replaces_the_address_and_beats_a_forget() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    db.merge(&web.announce("10.0.0.1:6514"));
+
+    let forgot = db.forget_member("fake-web-01");
+    assert!(db.members.get(&"fake-web-01".to_string()).is_none());
+
+    // web, still running, announces its new address after the forget
+    web.merge(&forgot);
+    db.merge(&web.announce("10.0.0.9:6514"));
+    assert_eq!(db.members.get(&"fake-web-01".to_string()).map(String::as_str), Some("10.0.0.9:6514"));
+}
+
+#[test]
+fn membership_is_part_of_the_digest() {
+    let mut web = MayaState::new("fake-web-01");
+    web.observe_visit("10.0.0.5", "web-01");
+    let mut db = MayaState::new("fake-db-01");
+    db.merge(&web);
+    web.announce("10.0.0.1:6514");
+
+    let diff = db.digest().diff(&web.digest());
+    assert!(diff.members && diff.attackers.is_empty());
+    db.merge(&web.extract(&diff));
+    assert_eq!(db.hash(), web.hash());
+}
@@ -0,0 +1,76 @@
+use maya_crdt::{Crdt, MayaState};
+
+fn replica(node: &str) -> MayaState {
+    let mut state = MayaState::new(node);
+    state.observe_visit("10.0.0.5", "web-01");
+    state.observe_visit("10.0.0.9", "web-01");
+    state.add_cred("root:toor");
+    state.add_session("web-01", "sess-abc", None);
+    state
+}
+
+#[test]
+fn identical_data_has_no_diff() {
+    let a = replica("fake-web-01");
+    let mut b = MayaState::new("fake-db-01");
+    b.merge(&a);
+
+    assert_eq!(a.digest().root, b.digest().root);
+    assert!(a.digest().diff(&b.digest()).is_empty());
+}
+
+#[test]
+fn diff_names_only_the_attackers_that_differ() {
+    let a = replica("fake-web-01");
+    let mut b = MayaState::new("fake-db-01");
+    b.merge(&a);
+    b.record_action("10.0.0.9", "web-01", "whoami");
+    b.observe_visit("10.0.0.12", "db-01");
+
+    let diff = a.digest().diff(&b.digest());
+    let ips: Vec<_> = diff.attackers.iter().map(String::as_str).collect();
+    assert_eq!(ips, ["10.0.0.12", "10.0.0.9"]);
+    assert!(!diff.stolen_creds);
+    assert!(!diff.active_sessions);
+
+    // The diff is symmetric
+    assert_eq!(b.digest().diff(&a.digest()), diff);
+}
+
+#[test]
+fn sections_are_flagged_separately() {
+    let a = replica("fake-web-01");
+    let mut b = MayaState::new("fake-db-01");
+    b.merge(&a);
+    b.add_cred("admin:admin123");
+    let diff = a.digest().diff(&b.digest());
+    assert!(diff.attackers.is_empty() && diff.stolen_creds && !diff.active_sessions);
+
+    let mut c = MayaState::new("fake-db-01");
+    c.merge(&a);
+    c.close_session("web-01");
+    let diff = a.digest().diff(&c.digest());
+    assert!(diff.attackers.is_empty() && !diff.stolen_creds && diff.active_sessions);
+}
+
+#[test]
+fn shipping_the_extract_both_ways_converges() {
+    let mut a = replica("fake-web-01");
+    let mut b = MayaState::new("fake-db-01");
+    b.merge(&a);
+    a.record_action("10.0.0.5", "web-01", "cat /etc/shadow");
+    a.add_cred("admin:admin123");
+    b.observe_visit("10.0.0.12", "db-01");
+    b.close_session("web-01");
+
+    let diff = a.digest().diff(&b.digest());
+    let to_b = a.extract(&diff);
+    let to_a = b.extract(&diff);
+    assert_eq!(to_b.attackers.len(), 1);
+    assert!(to_b.version.entries.is_empty());
+
+    a.merge(&to_a);
+    b.merge(&to_b);
+    assert_eq!(a.hash(), b.hash());
+    assert!(a.digest().diff(&b.digest()).is_empty());
+}
@@ -0,0 +1,60 @@
+use maya_crdt::crypto::StateKey;
+use maya_crdt::peers::{PeerRecords, MAX_BACKOFF};
+use maya_crdt::VersionVector;
+use std::time::Duration;
+
+mod common;
+use common::TempDir;
+
+const INTERVAL: Duration = Duration::from_secs(30);
+
+#[test]
+fn failures_back_off_exponentially_until_a_success() {
+    let mut records = PeerRecords::default();
+    let peer = "10.0.0.2:6514";
+    assert!(records.is_due(peer, 0));
+
+    let waits: Vec<_> = (0..4).map(|_| records.failed(peer, 1_000, "connection refused", INTERVAL).as_secs()).collect();
+    assert_eq!(waits, [30, 60, 120, 240]);
+    assert!(!records.is_due(peer, 1_000 + 239_999));
+    assert!(records.is_due(peer, 1_000 + 240_000));
+
+    for _ in 0..20 {
+        records.failed(peer, 1_000, "connection refused", INTERVAL);
+    }
+    assert_eq!(records.get(peer).unwrap().backoff(INTERVAL), MAX_BACKOFF);
+
+    let mut version = VersionVector::new();
+    version.observe("fake-db-01", 7);
+    records.succeeded(peer, 2_000, Some("fake-db-01"), Some(&version));
+    let record = records.get(peer).unwrap();
+    assert_eq!((record.failures, record.last_success, &record.version), (0, Some(2_000), &version));
+    assert_eq!(record.node_id.as_deref(), Some("fake-db-01"));
+    assert_eq!(record.last_error.as_deref(), Some("connection refused"));
+    assert!(records.is_due(peer, 2_000));
+}
+
+#[test]
+fn records_persist_sealed_and_drop_unlisted_peers() {
+    let dir = TempDir::new("peer-records");
+    let path = dir.file("state.peers");
+    let key = StateKey::generate();
+
+    let mut records = PeerRecords::default();
+    records.succeeded("fake-db-01", 1_000, None, None);
+    records.failed("fake-mail-01", 1_000, "timed out", INTERVAL);
+    records.save(&path, Some(&key)).unwrap();
+    assert!(!String::from_utf8_lossy(&std::fs::read(&path).unwrap()).contains("fake-mail-01"));
+
+    let mut loaded = PeerRecords::load(&path, Some(&key));
+    assert_eq!(loaded, records);
+    assert_eq!(PeerRecords::load(&path, None), PeerRecords::default());
+
+    // Like the delta buffer: plaintext is not trusted once a key is set
+    records.save(&path, None).unwrap();
+    assert_eq!(PeerRecords::load(&path, None), records);
+    assert_eq!(PeerRecords::load(&path, Some(&key)), PeerRecords::default());
+
+    loaded.retain(&["fake-db-01".to_string()]);
+    assert_eq!(loaded.peers.keys().collect::<Vec<_>>(), ["fake-db-01"]);
+}
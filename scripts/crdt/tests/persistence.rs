@@ -0,0 +1,187 @@
+use maya_crdt::{oplog_path, read_oplog, Codec, LoadError, MayaState, StateLock, SNAPSHOT_EVERY};
+use std::fs;
+use std::io::Write;
+use std::thread;
+
+mod common;
+use common::TempDir;
+
+#[test]
+fn concurrent_visits_are_not_lost() {
+    let dir = TempDir::new("concurrent");
+    let path = dir.file("state");
+    let writers = 32;
+
+    // Each thread does what one `syslogd-helper visit` invocation does
+    let handles: Vec<_> = (0..writers)
+        .map(|i| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let _lock = StateLock::acquire(&path).unwrap();
+                let mut state = MayaState::load(&path, "sensor").unwrap();
+                state.observe_visit(&format!("10.0.0.{}", i), "web");
+                state.save(&path).unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let state = MayaState::load(&path, "sensor").unwrap();
+    assert_eq!(state.attackers.len(), writers);
+    assert_eq!(state.clock.counter, writers as u64);
+}
+
+#[test]
+fn save_replaces_file_without_leaving_temp_files() {
+    let dir = TempDir::new("atomic");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    state.observe_visit("10.0.0.1", "web");
+    state.save(&path).unwrap();
+    state.observe_visit("10.0.0.2", "db");
+    state.save(&path).unwrap();
+
+    let loaded = MayaState::load(&path, "sensor").unwrap();
+    assert_eq!(loaded.attackers.len(), 2);
+
+    assert!(!dir.names().iter().any(|n| n.contains(".tmp.")));
+}
+
+#[test]
+fn corrupt_state_is_quarantined_not_reset() {
+    let dir = TempDir::new("corrupt");
+    let path = dir.file("state");
+    fs::write(&path, "{\"node_id\": \"sensor\", \"attackers\": {").unwrap();
+
+    let err = MayaState::load(&path, "sensor").unwrap_err();
+    let LoadError::Corrupt { quarantined, .. } = err else { panic!("expected Corrupt, got {:?}", err) };
+    assert!(quarantined.starts_with(&format!("{}.corrupt-", path)));
+    assert!(fs::read_to_string(&quarantined).unwrap().contains("attackers"));
+    assert!(!std::path::Path::new(&path).exists());
+}
+
+#[test]
+fn recover_restores_newest_good_backup() {
+    let dir = TempDir::new("recover");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+        state.observe_visit(ip, "web");
+        state.save(&path).unwrap();
+    }
+    fs::write(&path, "garbage").unwrap();
+
+    assert!(matches!(MayaState::load(&path, "sensor"), Err(LoadError::Corrupt { .. })));
+    // With the state file moved aside, a fresh start would lose history
+    assert!(matches!(MayaState::load(&path, "sensor"), Err(LoadError::Missing)));
+
+    let (recovered, backup) = MayaState::recover(&path, &Codec::default()).unwrap();
+    assert_eq!(backup, format!("{}.bak.1", path));
+    assert_eq!(recovered.attackers.len(), 2);
+    assert_eq!(MayaState::load(&path, "sensor").unwrap(), recovered);
+}
+
+#[test]
+fn recover_without_backups_fails() {
+    let dir = TempDir::new("nobackup");
+    let path = dir.file("state");
+    assert!(matches!(MayaState::recover(&path, &Codec::default()), Err(LoadError::NoBackup)));
+}
+
+#[test]
+fn logged_ops_are_replayed_on_load() {
+    let dir = TempDir::new("replay");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    let delta = state.observe_visit("10.0.0.1", "web");
+    state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    let delta = state.add_cred("root:toor");
+    state.commit(&path, "cred", &delta, &Codec::default()).unwrap();
+
+    // No snapshot was written: everything comes from the log
+    assert!(!std::path::Path::new(&path).exists());
+    assert_eq!(MayaState::load(&path, "sensor").unwrap(), state);
+    assert_eq!(read_oplog(&oplog_path(&path), None).len(), 2);
+}
+
+#[test]
+fn snapshot_starts_a_new_log() {
+    let dir = TempDir::new("snapshot");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    for i in 0..SNAPSHOT_EVERY {
+        let delta = state.observe_visit(&format!("10.0.1.{}", i), "web");
+        state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    }
+
+    assert!(!MayaState::has_unsnapshotted_ops(&path));
+    let ops = read_oplog(&format!("{}.1", oplog_path(&path)), None);
+    assert_eq!(ops.len(), SNAPSHOT_EVERY);
+    assert!(ops.iter().all(|logged| logged.op == "visit"));
+    assert_eq!(MayaState::load(&path, "sensor").unwrap(), state);
+}
+
+#[test]
+fn log_replaced_behind_commit_is_recounted() {
+    let dir = TempDir::new("recount");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    for i in 0..SNAPSHOT_EVERY - 1 {
+        let delta = state.observe_visit(&format!("10.0.1.{}", i), "web");
+        state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    }
+    fs::write(oplog_path(&path), "").unwrap();
+
+    // One entry in the log, so no snapshot yet
+    let delta = state.observe_visit("10.0.2.1", "web");
+    state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    assert!(MayaState::has_unsnapshotted_ops(&path));
+    assert_eq!(read_oplog(&oplog_path(&path), None).len(), 1);
+}
+
+#[test]
+fn torn_log_line_is_skipped() {
+    let dir = TempDir::new("torn");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    let delta = state.observe_visit("10.0.0.1", "web");
+    state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    let mut log = fs::OpenOptions::new().append(true).open(oplog_path(&path)).unwrap();
+    log.write_all(b"{\"op\":\"visit\",\"at\":1,\"del").unwrap();
+    assert_eq!(MayaState::load(&path, "sensor").unwrap(), state);
+
+    // The next op after the crash is not glued onto the torn line
+    let delta = state.observe_visit("10.0.0.2", "db");
+    state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    assert_eq!(MayaState::load(&path, "sensor").unwrap(), state);
+}
+
+#[test]
+fn recover_replays_ops_logged_after_the_backup() {
+    let dir = TempDir::new("recover-log");
+    let path = dir.file("state");
+
+    let mut state = MayaState::new("sensor");
+    state.snapshot(&path, &Codec::default()).unwrap();
+    let delta = state.observe_visit("10.0.0.1", "web");
+    state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+    state.snapshot(&path, &Codec::default()).unwrap();
+    let delta = state.observe_visit("10.0.0.2", "db");
+    state.commit(&path, "visit", &delta, &Codec::default()).unwrap();
+
+    // The newest snapshot rots; the backup before it plus both logs
+    // still hold every operation
+    fs::write(&path, "garbage").unwrap();
+    assert!(MayaState::load(&path, "sensor").is_err());
+
+    let (recovered, _) = MayaState::recover(&path, &Codec::default()).unwrap();
+    assert_eq!(recovered, state);
+}
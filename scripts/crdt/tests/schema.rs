@@ -0,0 +1,78 @@
+//! State files written by every earlier release load into the current
+//! format. Each fixture was written by the release that introduced its
+//! schema version, running the same commands:
+//!
+//!     visit 10.0.0.5 web-01; action 10.0.0.5 web-01 "cat /etc/passwd"
+//!     move 10.0.0.5 db-01; visit 10.0.0.5 db-01
+//!     action 10.0.0.5 db-01 "mysql -u root"
+//!     visit 10.0.0.9 web-01; move 10.0.0.9 web-01
+//!     cred root:toor; cred admin:admin123
+//!     session web-01 sess-abc; session db-01 sess-def
+
+use maya_crdt::{LoadError, MayaState, SCHEMA_VERSION};
+use std::fs;
+
+mod common;
+use common::TempDir;
+
+const NODE: &str = "fake-web-01";
+
+fn load_fixture(version: u64) -> MayaState {
+    let dir = TempDir::new(&format!("schema-v{}", version));
+    let path = dir.file("state");
+    let fixture = format!("{}/tests/fixtures/state_v{}.json", env!("CARGO_MANIFEST_DIR"), version);
+    fs::copy(&fixture, &path).unwrap();
+    MayaState::load(&path, NODE).unwrap_or_else(|e| panic!("v{}: {}", version, e))
+}
+
+#[test]
+fn every_previous_version_loads() {
+    for version in 1..=SCHEMA_VERSION {
+        let state = load_fixture(version);
+        let ctx = format!("v{}", version);
+        assert_eq!(state.node_id, NODE, "{}", ctx);
+        assert_eq!(state.version.get(NODE), 11, "{}", ctx);
+        assert_eq!(state.attackers.len(), 2, "{}", ctx);
+
+        let attacker = &state.attackers["10.0.0.5"];
+        let visited: Vec<_> = attacker.visited_decoys.elements.iter().cloned().collect();
+        assert_eq!(visited, ["db-01", "web-01"], "{}", ctx);
+        assert_eq!(attacker.location.values(), [&"db-01".to_string()], "{}", ctx);
+        let actions: Vec<_> = attacker.actions.iter()
+            .map(|(_, a)| (a.decoy.as_str(), a.action.as_str()))
+            .collect();
+        assert_eq!(actions, [("web-01", "cat /etc/passwd"), ("db-01", "mysql -u root")], "{}", ctx);
+
+        let creds: Vec<_> = state.stolen_creds.elements().into_iter().collect();
+        assert_eq!(creds, ["admin:admin123", "root:toor"], "{}", ctx);
+        let sessions: Vec<_> = state.live_sessions()
+            .map(|(host, session)| (host.as_str(), session.id.as_str()))
+            .collect();
+        assert_eq!(sessions, [("db-01", "sess-def"), ("web-01", "sess-abc")], "{}", ctx);
+    }
+}
+
+#[test]
+fn migrated_state_saves_as_current_version() {
+    let dir = TempDir::new("schema-save");
+    let path = dir.file("state");
+
+    let state = load_fixture(1);
+    state.save(&path).unwrap();
+    let saved: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(saved["schema_version"], SCHEMA_VERSION);
+    assert_eq!(MayaState::load(&path, NODE).unwrap(), state);
+}
+
+#[test]
+fn newer_version_is_refused_and_left_alone() {
+    let dir = TempDir::new("schema-newer");
+    let path = dir.file("state");
+    let mut value = MayaState::new(NODE).to_versioned_json().unwrap();
+    value["schema_version"] = (SCHEMA_VERSION + 1).into();
+    fs::write(&path, value.to_string()).unwrap();
+
+    let err = MayaState::load(&path, NODE).unwrap_err();
+    assert!(matches!(err, LoadError::Newer { found } if found == SCHEMA_VERSION + 1));
+    assert!(std::path::Path::new(&path).exists());
+}
@@ -0,0 +1,43 @@
+use maya_crdt::{Crdt, MayaState};
+use std::time::Duration;
+
+fn live(state: &MayaState) -> Vec<(String, String)> {
+    state.live_sessions().map(|(host, session)| (host.clone(), session.id.clone())).collect()
+}
+
+#[test]
+fn sessions_past_their_ttl_are_not_live_and_get_closed() {
+    let mut web = MayaState::new("fake-web-01");
+    web.add_session("web-01", "sess-abc", Some(Duration::ZERO));
+    web.add_session("db-01", "sess-def", Some(Duration::from_secs(600)));
+    web.add_session("mail-01", "sess-ghi", None);
+    assert_eq!(live(&web), [("db-01".into(), "sess-def".into()), ("mail-01".into(), "sess-ghi".into())]);
+
+    let mut db = MayaState::new("fake-db-01");
+    db.merge(&web);
+    let delta = web.expire_sessions().expect("web-01's session has expired");
+    assert!(web.active_sessions.get(&"web-01".to_string()).is_none());
+    assert!(web.expire_sessions().is_none());
+
+    // The close travels like any other update
+    db.merge(&delta);
+    assert!(db.active_sessions.get(&"web-01".to_string()).is_none());
+    assert_eq!(live(&db), live(&web));
+}
+
+#[test]
+fn a_closed_session_stays_closed_on_every_replica() {
+    let mut web = MayaState::new("fake-web-01");
+    let mut db = MayaState::new("fake-db-01");
+    web.add_session("web-01", "sess-abc", None);
+    db.merge(&web);
+
+    db.close_session("web-01");
+    web.merge(&db);
+    assert!(live(&web).is_empty() && live(&db).is_empty());
+
+    // A session opened after the close is a new one and wins
+    web.add_session("web-01", "sess-xyz", None);
+    db.merge(&web);
+    assert_eq!(live(&db), [("web-01".into(), "sess-xyz".into())]);
+}
@@ -0,0 +1,99 @@
+use maya_crdt::crypto::StateKey;
+use maya_crdt::signing::{is_signed, unsigned, NodeKey, SignatureError, TrustedKeys};
+use maya_crdt::{Codec, Encoding, LoadError, MayaState};
+use std::fs;
+
+mod common;
+use common::TempDir;
+
+fn delta_from(node: &str) -> MayaState {
+    let mut state = MayaState::new(node);
+    state.observe_visit("10.0.0.5", "web-01")
+}
+
+fn trusting(node: &str, key: &NodeKey) -> TrustedKeys {
+    let mut trusted = TrustedKeys::default();
+    trusted.add(node, key.verifying_key());
+    trusted
+}
+
+fn rejection(result: Result<MayaState, LoadError>) -> SignatureError {
+    match result {
+        Err(LoadError::Signature(e)) => e,
+        other => panic!("expected a signature error, got {:?}", other),
+    }
+}
+
+#[test]
+fn signed_payload_from_trusted_origin_decodes() {
+    let key = NodeKey::generate();
+    let codec = Codec { encoding: Encoding::Binary, key: Some(StateKey::generate()) };
+    let delta = delta_from("fake-web-01");
+    let signed = key.sign("fake-web-01", &delta.encode(&codec).unwrap());
+
+    assert!(is_signed(&signed));
+    assert_eq!(trusting("fake-web-01", &key).decode(&signed, &codec).unwrap(), delta);
+    // Nodes that do not verify still read it
+    assert_eq!(MayaState::decode(unsigned(&signed), &codec).unwrap(), delta);
+}
+
+#[test]
+fn untrusted_or_tampered_payloads_are_rejected() {
+    let key = NodeKey::generate();
+    let codec = Codec::default();
+    let payload = delta_from("fake-web-01").encode(&codec).unwrap();
+    let trusted = trusting("fake-web-01", &key);
+
+    assert!(matches!(rejection(trusted.decode(&payload, &codec)), SignatureError::Unsigned));
+
+    let forged = NodeKey::generate().sign("fake-web-01", &payload);
+    assert!(matches!(rejection(trusted.decode(&forged, &codec)), SignatureError::BadSignature(_)));
+
+    let stranger = key.sign("fake-db-01", &payload);
+    assert!(matches!(rejection(trusted.decode(&stranger, &codec)), SignatureError::UnknownSigner(n) if n == "fake-db-01"));
+
+    let mut tampered = key.sign("fake-web-01", &payload);
+    let last = tampered.len() - 2;
+    tampered[last] ^= 1;
+    assert!(matches!(rejection(trusted.decode(&tampered, &codec)), SignatureError::BadSignature(_)));
+
+    let truncated = &key.sign("fake-web-01", &payload)[..20];
+    assert!(matches!(rejection(trusted.decode(truncated, &codec)), SignatureError::Malformed));
+}
+
+#[test]
+fn a_node_cannot_sign_for_another() {
+    // A compromised decoy signs with its own valid key, but the state
+    // claims to be another node's
+    let key = NodeKey::generate();
+    let codec = Codec::default();
+    let signed = key.sign("fake-web-01", &delta_from("fake-db-01").encode(&codec).unwrap());
+    let err = rejection(trusting("fake-web-01", &key).decode(&signed, &codec));
+    assert!(matches!(err, SignatureError::WrongOrigin { signer, origin } if signer == "fake-web-01" && origin == "fake-db-01"));
+}
+
+#[test]
+fn key_files_round_trip() {
+    let dir = TempDir::new("signing-keys");
+    let key_path = dir.file("node.key");
+    let trusted_path = dir.file("trusted_keys");
+    let key = NodeKey::generate();
+    let old = NodeKey::generate();
+    key.save(&key_path).unwrap();
+    assert!(NodeKey::generate().save(&key_path).is_err());
+
+    fs::write(&trusted_path, format!(
+        "# node id  key\n\nfake-web-01 {}\nfake-web-01\t{}  # being replaced\n",
+        key.public_hex(), old.public_hex(),
+    )).unwrap();
+    let trusted = TrustedKeys::load(&trusted_path).unwrap();
+    let payload = delta_from("fake-web-01").encode(&Codec::default()).unwrap();
+    for signer in [NodeKey::load(&key_path).unwrap(), old] {
+        let signed = signer.sign("fake-web-01", &payload);
+        assert!(trusted.decode(&signed, &Codec::default()).is_ok());
+    }
+
+    fs::write(&trusted_path, "fake-web-01 nothex\n").unwrap();
+    let err = TrustedKeys::load(&trusted_path).unwrap_err();
+    assert!(err.to_string().contains("line 1"), "{}", err);
+}
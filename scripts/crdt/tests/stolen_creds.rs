@@ -0,0 +1,164 @@
+use maya_crdt::{oplog_path, read_oplog, Codec, Crdt, MayaState};
+
+mod common;
+use common::TempDir;
+
+fn creds(state: &MayaState) -> Vec<String> {
+    state.stolen_creds.elements().into_iter().collect()
+}
+
+#[test]
+fn remove_cred_retracts_credential() {
+    let mut a = MayaState::new("node-a");
+    a.add_cred("root:toor");
+    a.add_cred("admin:admin");
+
+    a.remove_cred("root:toor");
+
+    assert_eq!(creds(&a), vec!["admin:admin"]);
+}
+
+#[test]
+fn remove_propagates_through_delta() {
+    let mut a = MayaState::new("node-a");
+    let mut b = MayaState::new("node-b");
+
+    b.merge(&a.add_cred("root:toor"));
+    assert_eq!(creds(&b), vec!["root:toor"]);
+
+    b.merge(&a.remove_cred("root:toor"));
+    assert!(creds(&b).is_empty());
+}
+
+#[test]
+fn concurrent_add_survives_remove() {
+    let mut a = MayaState::new("node-a");
+    let mut b = MayaState::new("node-b");
+
+    a.add_cred("root:toor");
+    b.merge(&a);
+
+    // Concurrently: b retracts the credential while a sees it stolen again
+    b.remove_cred("root:toor");
+    a.add_cred("root:toor");
+
+    let mut ab = a.clone();
+    ab.merge(&b);
+    let mut ba = b.clone();
+    ba.merge(&a);
+
+    assert_eq!(creds(&ab), vec!["root:toor"]);
+    assert_eq!(creds(&ba), vec!["root:toor"]);
+}
+
+#[test]
+fn remove_does_not_affect_unobserved_add() {
+    let mut a = MayaState::new("node-a");
+    let mut b = MayaState::new("node-b");
+
+    // b has never seen a's add, so its remove tombstones nothing
+    a.add_cred("root:toor");
+    b.remove_cred("root:toor");
+
+    b.merge(&a);
+    assert_eq!(creds(&b), vec!["root:toor"]);
+}
+
+#[test]
+fn removed_credential_stays_removed_after_compaction() {
+    let mut a = MayaState::new("node-a");
+    let mut b = MayaState::new("node-b");
+
+    a.add_cred("root:toor");
+    b.merge(&a);
+    b.remove_cred("root:toor");
+
+    // Both replicas exchange state, so the remove is stable everywhere
+    a.merge(&b);
+    b.merge(&a);
+
+    assert_eq!(b.compact(), 1);
+    assert_eq!(b.stolen_creds.tombstones(), 0);
+
+    a.merge(&b);
+    b.merge(&a);
+    assert!(creds(&a).is_empty());
+    assert!(creds(&b).is_empty());
+}
+
+#[test]
+fn unknown_replica_holds_compaction_back() {
+    let mut a = MayaState::new("node-a");
+    let mut b = MayaState::new("node-b");
+    let mut c = MayaState::new("node-c");
+
+    a.add_cred("root:toor");
+    b.merge(&a);
+    b.remove_cred("root:toor");
+    a.merge(&b);
+    // a learns of c's updates through b, but c never said what it has seen
+    b.merge(&c.observe_visit("10.0.0.5", "c-01"));
+    a.merge(&b);
+
+    assert!(a.known_replicas().contains("node-c"));
+    assert_eq!(a.compact(), 0);
+    assert_eq!(a.stolen_creds.tombstones(), 1);
+
+    // Once c is decommissioned and forgotten it stops holding things back
+    a.forget_member("node-c");
+    assert!(!a.known_replicas().contains("node-c"));
+    b.merge(&a);
+    a.merge(&b);
+    assert_eq!(a.compact(), 1);
+}
+
+#[test]
+fn stripped_deltas_cannot_resurrect_a_compacted_credential() {
+    let mut a = MayaState::new("node-a");
+    let mut b = MayaState::new("node-b");
+
+    // a's delta for the add is still buffered when the remove settles
+    let buffered = a.add_cred("root:toor");
+    b.merge(&a);
+    b.remove_cred("root:toor");
+    a.merge(&b);
+    b.merge(&a);
+    assert_eq!(a.compact(), 1);
+    assert_eq!(b.compact(), 1);
+
+    let mut replayed = b.clone();
+    replayed.merge(&buffered);
+    assert_eq!(creds(&replayed), vec!["root:toor"], "an unstripped delta brings it back");
+
+    let mut stripped = buffered.clone();
+    a.strip_compacted(&mut stripped);
+    b.merge(&stripped);
+    assert!(creds(&b).is_empty());
+}
+
+#[test]
+fn compaction_strips_the_op_logs() {
+    let dir = TempDir::new("compact-oplog");
+    let path = dir.file("state");
+    let codec = Codec::default();
+    let mut state = MayaState::new("node-a");
+    let delta = state.add_cred("root:toor");
+    state.commit(&path, "cred", &delta, &codec).unwrap();
+    state.snapshot(&path, &codec).unwrap();
+    let delta = state.remove_cred("root:toor");
+    state.commit(&path, "uncred", &delta, &codec).unwrap();
+
+    // A lone node: every remove is stable as soon as it is made
+    assert_eq!(state.compact(), 1);
+    state.snapshot(&path, &codec).unwrap();
+    state.strip_oplogs(&path, &codec).unwrap();
+
+    let mut replayed = state.clone();
+    for generation in [1, 2] {
+        for logged in read_oplog(&format!("{}.{}", oplog_path(&path), generation), None) {
+            replayed.merge(&logged.delta);
+        }
+    }
+    assert!(creds(&replayed).is_empty());
+    assert_eq!(replayed.stolen_creds, state.stolen_creds);
+}
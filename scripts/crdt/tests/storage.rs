@@ -0,0 +1,148 @@
+use maya_crdt::storage::{FileStorage, MemoryStorage, Storage};
+use maya_crdt::{Codec, MayaState};
+
+mod common;
+use common::TempDir;
+
+// What one CLI invocation after another does against `storage`
+fn exercise(storage: &dyn Storage) {
+    let fresh = storage.load("fake-web-01").unwrap();
+    assert_eq!(fresh, MayaState::new("fake-web-01"));
+
+    let mut state = fresh;
+    let delta = state.observe_visit("10.0.0.5", "web-01");
+    storage.commit(&state, "visit", &delta).unwrap();
+    let delta = state.observe_visit("10.0.0.9", "db-01");
+    storage.commit(&state, "visit", &delta).unwrap();
+    let delta = state.add_cred("root:toor");
+    storage.commit(&state, "cred", &delta).unwrap();
+    assert_eq!(storage.load("fake-web-01").unwrap(), state);
+
+    // Touching one attacker leaves the other as it was
+    let delta = state.record_action("10.0.0.5", "web-01", "cat /etc/passwd");
+    storage.commit(&state, "action", &delta).unwrap();
+    let attacker = storage.attacker("10.0.0.5").unwrap().unwrap();
+    assert_eq!(attacker.actions.len(), 1);
+    assert_eq!(storage.attacker("10.0.0.9").unwrap().as_ref(), state.attackers.get("10.0.0.9"));
+    assert!(storage.attacker("10.0.0.1").unwrap().is_none());
+
+    // A snapshot replaces what is stored
+    let mut trimmed = state.clone();
+    trimmed.attackers.remove("10.0.0.9");
+    storage.snapshot(&trimmed).unwrap();
+    assert_eq!(storage.load("fake-web-01").unwrap(), trimmed);
+}
+
+#[test]
+fn file_storage() {
+    let dir = TempDir::new("storage-file");
+    exercise(&FileStorage::new(&dir.file("state"), Codec::default()));
+}
+
+#[test]
+fn memory_storage() {
+    exercise(&MemoryStorage::default());
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use maya_crdt::crypto::StateKey;
+    use maya_crdt::storage::SqliteStorage;
+    use maya_crdt::LoadError;
+    use std::fs;
+
+    #[test]
+    fn sqlite_storage() {
+        exercise(&SqliteStorage::open_in_memory(None).unwrap());
+    }
+
+    #[test]
+    fn sqlite_storage_persists_and_seals_rows() {
+        let dir = TempDir::new("storage-sqlite");
+        let path = dir.file("state.db");
+        let key = StateKey::generate();
+
+        let mut state = MayaState::new("fake-web-01");
+        {
+            let db = SqliteStorage::open(&path, Some(key.clone())).unwrap();
+            let delta = state.observe_visit("10.0.0.5", "web-01");
+            db.commit(&state, "visit", &delta).unwrap();
+            let delta = state.add_cred("root:toor");
+            db.commit(&state, "cred", &delta).unwrap();
+        }
+
+        let raw = String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned();
+        assert!(!raw.contains("root:toor") && !raw.contains("web-01"));
+
+        let db = SqliteStorage::open(&path, Some(key)).unwrap();
+        assert_eq!(db.load("fake-web-01").unwrap(), state);
+        assert!(SqliteStorage::open(&path, None).unwrap().load("fake-web-01").is_err());
+    }
+
+    // A v1 state as an older release would have split it into rows at
+    // `path`, and the state it migrates to
+    fn v1_database(path: &str) -> (SqliteStorage, MayaState) {
+        let fixture = format!("{}/tests/fixtures/state_v1.json", env!("CARGO_MANIFEST_DIR"));
+        let mut meta: serde_json::Value = serde_json::from_slice(&fs::read(&fixture).unwrap()).unwrap();
+        let attackers = meta.as_object_mut().unwrap().remove("attackers").unwrap();
+        let db = SqliteStorage::open(path, None).unwrap();
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute("INSERT INTO meta (id, schema_version, data) VALUES (0, 1, ?1)", [meta.to_string().into_bytes()]).unwrap();
+        for (ip, attacker) in attackers.as_object().unwrap() {
+            conn.execute("INSERT INTO attackers (ip, data) VALUES (?1, ?2)", (ip, attacker.to_string().into_bytes())).unwrap();
+        }
+
+        (db, MayaState::load(&fixture, "fake-web-01").unwrap())
+    }
+
+    #[test]
+    fn rows_from_an_older_schema_load_one_at_a_time_through_the_migrations() {
+        let dir = TempDir::new("storage-sqlite-v1");
+        let (db, expected) = v1_database(&dir.file("state.db"));
+        assert_eq!(db.load("fake-web-01").unwrap(), expected);
+        assert_eq!(db.attacker("10.0.0.5").unwrap().as_ref(), expected.attackers.get("10.0.0.5"));
+        assert_eq!(db.attacker("10.0.0.1").unwrap(), None);
+
+        let only = db.load_attackers("fake-web-01", &["10.0.0.9", "10.0.0.1"]).unwrap();
+        assert_eq!(only.attackers.keys().collect::<Vec<_>>(), ["10.0.0.9"]);
+        assert_eq!(only.attackers["10.0.0.9"], expected.attackers["10.0.0.9"]);
+        assert_eq!(only.stolen_creds, expected.stolen_creds);
+        assert!(db.load_attackers("fake-web-01", &[]).unwrap().attackers.is_empty());
+    }
+
+    #[test]
+    fn committing_one_row_upgrades_the_untouched_ones() {
+        let dir = TempDir::new("storage-sqlite-v1-commit");
+        let (db, expected) = v1_database(&dir.file("state.db"));
+
+        let mut state = db.load_attackers("fake-web-01", &["10.0.0.77"]).unwrap();
+        let delta = state.observe_visit("10.0.0.77", "web-01");
+        db.commit(&state, "visit", &delta).unwrap();
+
+        let loaded = db.load("fake-web-01").unwrap();
+        assert_eq!(db.attacker("10.0.0.5").unwrap().as_ref(), expected.attackers.get("10.0.0.5"));
+        assert_eq!(loaded.attackers.len(), expected.attackers.len() + 1);
+        for (ip, attacker) in &expected.attackers {
+            assert_eq!(&loaded.attackers[ip], attacker, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn plaintext_rows_are_refused_until_sealed() {
+        let dir = TempDir::new("storage-sqlite-upgrade");
+        let path = dir.file("state.db");
+        let key = StateKey::generate();
+
+        let mut state = MayaState::new("fake-web-01");
+        state.observe_visit("10.0.0.5", "web-01");
+        state.add_cred("root:toor");
+        SqliteStorage::open(&path, None).unwrap().snapshot(&state).unwrap();
+
+        let db = SqliteStorage::open(&path, Some(key)).unwrap();
+        assert!(matches!(db.load("fake-web-01"), Err(LoadError::Plaintext)));
+        assert_eq!(db.seal_plaintext().unwrap(), ["meta", "attacker 10.0.0.5"]);
+        assert_eq!(db.load("fake-web-01").unwrap(), state);
+        assert!(db.seal_plaintext().unwrap().is_empty());
+    }
+}
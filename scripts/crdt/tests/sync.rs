@@ -0,0 +1,240 @@
+use maya_crdt::signing::{NodeKey, TrustedKeys};
+use maya_crdt::sync::{self, peer_addr, peer_host, Frame, Hello, Replica, DEFAULT_PORT, MAX_CONNECTIONS, MAX_FRAME, MAX_HANDSHAKE_FRAME};
+use maya_crdt::{Codec, Crdt, MayaState, StateDigest, VersionVector};
+use std::io::{Cursor, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+// Merges into and serves from `state`, the way the daemon's listener does
+// with its state file
+struct TestReplica(Arc<Mutex<MayaState>>);
+
+impl Replica for TestReplica {
+    fn merge(&self, _: &Hello, payload: &[u8]) -> Result<VersionVector, String> {
+        let remote = MayaState::decode(payload, &Codec::default()).map_err(|e| e.to_string())?;
+        let mut state = self.0.lock().unwrap();
+        state.merge(&remote);
+        Ok(state.version.clone())
+    }
+
+    fn pull(&self, _: &Hello, remote: &StateDigest) -> Result<(StateDigest, Vec<u8>), String> {
+        let state = self.0.lock().unwrap();
+        let digest = state.digest();
+        let payload = state.extract(&digest.diff(remote)).encode(&Codec::default()).map_err(|e| e.to_string())?;
+        Ok((digest, payload))
+    }
+}
+
+// A listener on a free local port serving `state` to fake-web-01, which
+// signs in with the key returned
+fn serve_into(state: Arc<Mutex<MayaState>>) -> (String, NodeKey) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let node_id = state.lock().unwrap().node_id.clone();
+    let key = NodeKey::generate();
+    let mut trusted = TrustedKeys::default();
+    trusted.add("fake-web-01", key.verifying_key());
+    sync::spawn_listener(listener, node_id, Arc::new(trusted), Arc::new(TestReplica(state)), |_| {});
+    (addr, key)
+}
+
+fn hello(node_id: &str, protocol: u32) -> Frame {
+    Frame::Hello(Hello { node_id: node_id.into(), protocol, challenge: None })
+}
+
+#[test]
+fn push_is_merged_and_acked() {
+    let server = Arc::new(Mutex::new(MayaState::new("fake-db-01")));
+    let (addr, key) = serve_into(server.clone());
+
+    let mut local = MayaState::new("fake-web-01");
+    let delta = local.observe_visit("10.0.0.5", "web-01");
+    let (hello, ack) = sync::push(&addr, &local.node_id, &key, delta.encode(&Codec::default()).unwrap()).unwrap();
+
+    assert_eq!(hello.node_id, "fake-db-01");
+    assert_eq!(ack.version.get("fake-web-01"), 1);
+    assert!(server.lock().unwrap().attackers.contains_key("10.0.0.5"));
+    assert_eq!(sync::probe(&addr, "fake-web-01").unwrap().node_id, "fake-db-01");
+}
+
+#[test]
+fn bad_payload_is_refused_with_a_reason() {
+    let server = Arc::new(Mutex::new(MayaState::new("fake-db-01")));
+    let (addr, key) = serve_into(server.clone());
+
+    let err = sync::push(&addr, "fake-web-01", &key, b"not a state".to_vec()).unwrap_err();
+    assert!(err.to_string().starts_with("peer refused:"), "{}", err);
+    assert_eq!(*server.lock().unwrap(), MayaState::new("fake-db-01"));
+}
+
+#[test]
+fn other_protocol_versions_are_refused() {
+    let (addr, _) = serve_into(Arc::new(Mutex::new(MayaState::new("fake-db-01"))));
+    // Protocols 1 and 2 had no sign-in
+    for protocol in [1, 2, 99] {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        hello("fake-web-01", protocol).write_to(&mut stream).unwrap();
+        let refused = Frame::read_from(&mut stream).unwrap();
+        assert!(matches!(&refused, Frame::Error(msg) if msg.contains(&format!("protocol {}", protocol))), "{:?}", refused);
+    }
+}
+
+#[test]
+fn clients_that_do_not_sign_in_get_nothing() {
+    let server = Arc::new(Mutex::new(MayaState::new("fake-db-01")));
+    server.lock().unwrap().add_cred("root:toor");
+    let (addr, _) = serve_into(server.clone());
+    let mut local = MayaState::new("fake-web-01");
+    let delta = local.observe_visit("10.0.0.5", "web-01");
+
+    let requests = [Frame::Pull(local.digest()), Frame::Push(delta.encode(&Codec::default()).unwrap())];
+    for request in requests {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        hello("fake-web-01", sync::PROTOCOL_VERSION).write_to(&mut stream).unwrap();
+        assert!(matches!(Frame::read_from(&mut stream).unwrap(), Frame::Hello(Hello { challenge: Some(_), .. })));
+        request.write_to(&mut stream).unwrap();
+        assert!(matches!(Frame::read_from(&mut stream).unwrap(), Frame::Error(msg) if msg == "expected auth"));
+        assert!(Frame::read_from(&mut stream).is_err());
+    }
+    assert!(server.lock().unwrap().attackers.is_empty());
+}
+
+#[test]
+fn untrusted_and_mislabelled_sign_ins_are_refused() {
+    let server = Arc::new(Mutex::new(MayaState::new("fake-db-01")));
+    let (addr, key) = serve_into(server.clone());
+    let mut local = MayaState::new("fake-web-01");
+    let payload = local.observe_visit("10.0.0.5", "web-01").encode(&Codec::default()).unwrap();
+
+    let untrusted = sync::push(&addr, "fake-web-01", &NodeKey::generate(), payload.clone()).unwrap_err();
+    assert!(untrusted.to_string().contains("not authenticated: bad signature"), "{}", untrusted);
+    // fake-web-01's key does not make it fake-mail-01
+    let mislabelled = sync::push(&addr, "fake-mail-01", &key, payload.clone()).unwrap_err();
+    assert!(mislabelled.to_string().contains("not authenticated"), "{}", mislabelled);
+
+    // A signature over anything but this connection's challenge
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    hello("fake-web-01", sync::PROTOCOL_VERSION).write_to(&mut stream).unwrap();
+    Frame::read_from(&mut stream).unwrap();
+    Frame::Auth(key.sign("fake-web-01", &payload)).write_to(&mut stream).unwrap();
+    assert!(matches!(Frame::read_from(&mut stream).unwrap(), Frame::Error(msg) if msg.contains("wrong challenge")));
+    assert!(server.lock().unwrap().attackers.is_empty());
+}
+
+#[test]
+fn large_frames_are_refused_before_sign_in() {
+    let (addr, _) = serve_into(Arc::new(Mutex::new(MayaState::new("fake-db-01"))));
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    stream.write_all(&(MAX_HANDSHAKE_FRAME as u32 + 1).to_be_bytes()).unwrap();
+    stream.write_all(&[1; 64]).unwrap();
+    // The server hangs up without reading the body or answering
+    assert!(Frame::read_from(&mut stream).is_err());
+}
+
+#[test]
+fn connections_beyond_the_limit_are_turned_away() {
+    let (addr, key) = serve_into(Arc::new(Mutex::new(MayaState::new("fake-db-01"))));
+    let mut idle: Vec<_> = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(&addr).unwrap()).collect();
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    assert!(matches!(Frame::read_from(&mut stream).unwrap(), Frame::Error(msg) if msg.contains("busy")));
+
+    // A slot frees up once a connection closes
+    drop(idle.pop());
+    let served = (0..50).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        sync::push(&addr, "fake-web-01", &key, MayaState::new("fake-web-01").encode(&Codec::default()).unwrap()).is_ok()
+    });
+    assert!(served);
+}
+
+// Two replicas that diverged while partitioned
+fn partitioned() -> (MayaState, MayaState) {
+    let mut shared = MayaState::new("fake-web-01");
+    shared.observe_visit("10.0.0.5", "web-01");
+    let mut web = shared.clone();
+    let mut db = MayaState::new("fake-db-01");
+    db.merge(&shared);
+
+    web.record_action("10.0.0.5", "web-01", "cat /etc/passwd");
+    web.add_cred("root:toor");
+    db.observe_visit("10.0.0.9", "db-01");
+    (web, db)
+}
+
+#[test]
+fn pull_fetches_only_what_differs() {
+    let (web, db) = partitioned();
+    let (addr, key) = serve_into(Arc::new(Mutex::new(db.clone())));
+
+    let (pulled, ack) = sync::push_pull(&addr, "fake-web-01", &key, &web.digest(), |_| Ok(None)).unwrap();
+    assert!(ack.is_none());
+    assert_eq!(pulled.digest, db.digest());
+    let part = MayaState::decode(&pulled.payload, &Codec::default()).unwrap();
+    // 10.0.0.5 differs (web recorded an action), 10.0.0.9 is db's alone
+    assert_eq!(part.attackers.keys().collect::<Vec<_>>(), ["10.0.0.5", "10.0.0.9"]);
+
+    let mut healed = web.clone();
+    healed.merge(&part);
+    assert!(healed.attackers.contains_key("10.0.0.9"));
+    assert!(healed.stolen_creds.elements().contains("root:toor"));
+}
+
+#[test]
+fn push_pull_converges_both_sides() {
+    let (web, db) = partitioned();
+    let server = Arc::new(Mutex::new(db));
+    let (addr, key) = serve_into(server.clone());
+
+    let digest = web.digest();
+    let (pulled, ack) = sync::push_pull(&addr, "fake-web-01", &key, &digest, |pulled| {
+        let diff = digest.diff(&pulled.digest);
+        Ok(Some(web.extract(&diff).encode(&Codec::default()).unwrap()))
+    }).unwrap();
+    assert!(ack.is_some());
+
+    let mut web = web;
+    web.merge(&MayaState::decode(&pulled.payload, &Codec::default()).unwrap());
+    assert_eq!(web.hash(), server.lock().unwrap().hash());
+}
+
+#[test]
+fn frames_round_trip_and_bad_lengths_are_rejected() {
+    let frames = [
+        hello("fake-web-01", sync::PROTOCOL_VERSION),
+        Frame::Hello(Hello { node_id: "fake-db-01".into(), protocol: sync::PROTOCOL_VERSION, challenge: Some("00ff".into()) }),
+        Frame::Push(vec![0, 1, 2, 255]),
+        Frame::Auth(vec![7; 100]),
+        Frame::Error("nope".into()),
+    ];
+    let mut wire = Vec::new();
+    for frame in &frames {
+        frame.write_to(&mut wire).unwrap();
+    }
+    let mut reader = Cursor::new(wire);
+    for frame in &frames {
+        assert_eq!(&Frame::read_from(&mut reader).unwrap(), frame);
+    }
+
+    let mut oversized = Vec::new();
+    oversized.write_all(&(MAX_FRAME as u32 + 1).to_be_bytes()).unwrap();
+    assert!(Frame::read_from(&mut Cursor::new(oversized)).is_err());
+    let mut push = Vec::new();
+    Frame::Push(vec![0; 100]).write_to(&mut push).unwrap();
+    assert!(Frame::read_limited(&mut Cursor::new(&push), 100).is_err());
+    assert!(Frame::read_limited(&mut Cursor::new(&push), 101).is_ok());
+    // A length the body never makes up
+    assert!(Frame::read_from(&mut Cursor::new(vec![0, 0, 1, 0, 2, 0])).is_err());
+    assert!(Frame::read_from(&mut Cursor::new(vec![0, 0, 0, 2, 9, 0])).is_err());
+}
+
+#[test]
+fn peer_entries_default_the_port() {
+    assert_eq!(peer_addr("fake-db-01"), format!("fake-db-01:{}", DEFAULT_PORT));
+    assert_eq!(peer_addr("10.0.0.2:7000"), "10.0.0.2:7000");
+    assert_eq!(peer_host("10.0.0.2:7000"), "10.0.0.2");
+    assert_eq!(peer_host("fake-db-01"), "fake-db-01");
+    assert_eq!(sync::resolve("127.0.0.1"), [format!("127.0.0.1:{}", DEFAULT_PORT).parse().unwrap()]);
+    assert!(sync::resolve("localhost:7000").contains(&"127.0.0.1:7000".parse().unwrap()));
+    assert!(sync::resolve("no-such-host.invalid").is_empty());
+}